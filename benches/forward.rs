@@ -0,0 +1,78 @@
+//! Compares userspace copy and splice based forwarding on loopback.
+//!
+//! Run with `cargo bench --bench forward`, the amount of transferred data can be changed
+//! through the `FORWARD_BENCH_MB` environment variable (256 MiB by default).
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use port_plumber::forward::{forward_sockets, ForwardOptions};
+use port_plumber::net::Stream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    let megabytes: usize = std::env::var("FORWARD_BENCH_MB").ok()
+        .and_then(|mb| mb.parse().ok())
+        .unwrap_or(256);
+
+    for zero_copy in [false, true] {
+        let elapsed = run(megabytes, zero_copy).await?;
+        let label = if zero_copy { "splice" } else { "copy" };
+        println!(
+            "{label:>6}: {megabytes} MiB in {:.3}s ({:.1} MiB/s)",
+            elapsed.as_secs_f64(),
+            megabytes as f64 / elapsed.as_secs_f64(),
+        );
+    }
+    Ok(())
+}
+
+async fn run(megabytes: usize, zero_copy: bool) -> anyhow::Result<Duration> {
+    let sink = TcpListener::bind("127.0.0.1:0").await?;
+    let sink_addr = sink.local_addr()?;
+    let sink_task = tokio::spawn(async move {
+        let (mut stream, _) = sink.accept().await?;
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut total = 0;
+        loop {
+            let read = stream.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            total += read;
+        }
+        anyhow::Ok(total)
+    });
+
+    let proxy = TcpListener::bind("127.0.0.1:0").await?;
+    let proxy_addr = proxy.local_addr()?;
+    let proxy_task = tokio::spawn(async move {
+        let (incoming, _) = proxy.accept().await?;
+        let outgoing = TcpStream::connect(sink_addr).await?;
+        let options = ForwardOptions { zero_copy, ..ForwardOptions::default() };
+        forward_sockets(Stream::Tcp(incoming), Stream::Tcp(outgoing), &options).await?;
+        anyhow::Ok(())
+    });
+
+    let start = Instant::now();
+    send(proxy_addr, megabytes).await?;
+    let received = sink_task.await??;
+    let elapsed = start.elapsed();
+    proxy_task.await??;
+    anyhow::ensure!(received == megabytes * 1024 * 1024, "received {received} bytes");
+    Ok(elapsed)
+}
+
+async fn send(addr: SocketAddr, megabytes: usize) -> anyhow::Result<()> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let chunk = vec![0x5au8; CHUNK_SIZE];
+    for _ in 0..megabytes * 1024 * 1024 / CHUNK_SIZE {
+        stream.write_all(&chunk).await?;
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use handlebars::Handlebars;
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::config::AccessLogConfig;
+use crate::forward::Transferred;
+
+const TEMPLATE_NAME: &str = "entry";
+
+/// Writes an entry for each connection of a plumbing, either as a JSON line or with a template
+#[derive(Debug)]
+pub struct AccessLog {
+    template: Option<Handlebars<'static>>,
+    output: Output,
+}
+
+/// Access log shared by the sockets of a plumbing, along with the names its entries are written for
+#[derive(Clone)]
+pub struct SocketAccessLog {
+    log: Arc<AccessLog>,
+    plumbing: Arc<str>,
+    socket: Arc<str>,
+}
+
+#[derive(Debug)]
+enum Output {
+    Stdout,
+    /// Opened in append mode, each entry is a single write
+    File(Mutex<File>),
+}
+
+/// What is known about a connection once it is closed
+pub struct ConnectionReport {
+    pub peer: String,
+    pub target: Option<String>,
+    pub transferred: Transferred,
+    /// The connection started the resource
+    pub cold_start: bool,
+    pub close_reason: CloseReason,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// Both sides closed the connection
+    Closed,
+    /// Idle timeout or maximum duration reached
+    Timeout,
+    /// Refused by the connection limits
+    Refused,
+    /// No target accepted the connection
+    ConnectError,
+    /// Refused, reset or blackholed by fault injection
+    Fault,
+    Error,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    timestamp: String,
+    plumbing: &'a str,
+    socket: &'a str,
+    peer: &'a str,
+    target: Option<&'a str>,
+    /// Bytes received from the client
+    bytes_in: u64,
+    /// Bytes sent to the client
+    bytes_out: u64,
+    duration_millis: u64,
+    cold_start: bool,
+    close_reason: CloseReason,
+    error: Option<&'a str>,
+}
+
+impl AccessLog {
+    pub fn open(config: &AccessLogConfig) -> anyhow::Result<Self> {
+        let template = config.format.as_ref()
+            .map(|format| {
+                let mut handlebars = Handlebars::new();
+                handlebars.register_escape_fn(handlebars::no_escape);
+                handlebars.register_template_string(TEMPLATE_NAME, format)?;
+                anyhow::Ok(handlebars)
+            })
+            .transpose()
+            .context("Invalid access log format")?;
+        let output = match &config.path {
+            Some(path) => Output::File(Mutex::new(OpenOptions::new().create(true).append(true).open(path)
+                .with_context(|| format!("Error opening access log {path:?}"))?)),
+            None => Output::Stdout,
+        };
+        Ok(Self { template, output })
+    }
+
+    /// Log of the connections of the socket `socket` of plumbing `plumbing`
+    pub fn socket(self: &Arc<Self>, plumbing: &str, socket: &str) -> SocketAccessLog {
+        SocketAccessLog { log: self.clone(), plumbing: plumbing.into(), socket: socket.into() }
+    }
+
+    fn write(&self, entry: &Entry) -> anyhow::Result<()> {
+        let mut line = match &self.template {
+            Some(template) => template.render(TEMPLATE_NAME, entry)?.into_bytes(),
+            None => serde_json::to_vec(entry)?,
+        };
+        line.push(b'\n');
+        match &self.output {
+            Output::Stdout => io::stdout().lock().write_all(&line)?,
+            Output::File(file) => file.lock().expect("Broken access log mutex").write_all(&line)?,
+        }
+        Ok(())
+    }
+}
+
+impl SocketAccessLog {
+    /// Logs a connection accepted at `accepted` and lasted `duration`
+    pub fn log(&self, report: &ConnectionReport, accepted: SystemTime, duration: Duration) {
+        let entry = Entry {
+            timestamp: OffsetDateTime::from(accepted).format(&Rfc3339).unwrap_or_default(),
+            plumbing: &self.plumbing,
+            socket: &self.socket,
+            peer: &report.peer,
+            target: report.target.as_deref(),
+            bytes_in: report.transferred.upstream,
+            bytes_out: report.transferred.downstream,
+            duration_millis: duration.as_millis() as u64,
+            cold_start: report.cold_start,
+            close_reason: report.close_reason,
+            error: report.error.as_deref(),
+        };
+        if let Err(err) = self.log.write(&entry) {
+            log::warn!("Error writing access log of {}/{} - {err:#}", self.plumbing, self.socket);
+        }
+    }
+}
+
+impl ConnectionReport {
+    pub fn new(peer: String) -> Self {
+        Self {
+            peer,
+            target: None,
+            transferred: Transferred::default(),
+            cold_start: false,
+            close_reason: CloseReason::Error,
+            error: None,
+        }
+    }
+}
@@ -1,26 +1,48 @@
 use std::fs;
 use std::net::IpAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use anyhow::Context;
-use axum::{Json, Router, Server, ServiceExt};
-use axum::extract::{State};
+use axum::{Json, Router, Server};
+use axum::extract::{Path as UrlPath, State};
+use axum::http::StatusCode;
 use axum::routing::{get, IntoMakeService};
-use hyperlocal::{SocketIncoming, UnixServerExt};
-use crate::plumber::Plumber;
+use hyperlocal::SocketIncoming;
+use crate::capture::Capture;
+use crate::config::{FaultConfig, ShapingConfig};
+use crate::plumber::{Plumber, PlumbingSummary};
 use crate::resolver::NameResolver;
 
-pub fn build_server(path: impl AsRef<Path>, name_resolver: NameResolver) -> anyhow::Result<Server<SocketIncoming, IntoMakeService<Router>>> {
-    if path.as_ref().exists() {
-        fs::remove_file(path.as_ref())
-            .context("Could not remove old socket!")?;
-    }
+#[derive(Clone)]
+struct ApiState {
+    name_resolver: NameResolver,
+    plumber: Plumber,
+}
+
+/// Builds the control server, `inherited` is used instead of binding `path` when provided by socket activation
+pub fn build_server(path: impl AsRef<Path>, inherited: Option<std::os::unix::net::UnixListener>, name_resolver: NameResolver, plumber: Plumber) -> anyhow::Result<Server<SocketIncoming, IntoMakeService<Router>>> {
+    let incoming = match inherited {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            SocketIncoming::from_listener(tokio::net::UnixListener::from_std(listener)?)
+        }
+        None => {
+            if path.as_ref().exists() {
+                fs::remove_file(path.as_ref())
+                    .context("Could not remove old socket!")?;
+            }
+            SocketIncoming::bind(path)?
+        }
+    };
 
     let app = Router::new()
         .route("/list", get(list_endpoints))
         .route("/resolve/:name", get(resolve_endpoint))
-        .with_state(name_resolver);
+        .route("/shaping/:name/:socket", get(get_shaping).put(set_shaping).delete(clear_shaping))
+        .route("/faults/:name/:socket", get(get_faults).put(set_faults).delete(clear_faults))
+        .route("/capture/:name", axum::routing::put(start_capture).delete(stop_capture))
+        .with_state(ApiState { name_resolver, plumber });
 
-    let srv = axum::Server::bind_unix(path)?
+    let srv = axum::Server::builder(incoming)
         .serve(app.into_make_service());
 
     Ok(srv)
@@ -31,16 +53,195 @@ pub struct Endpoint {
     pub ip: IpAddr,
 }
 
-async fn list_endpoints() -> Json<Vec<Endpoint>> {
-    Json(vec![])
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct PlumbingEntry {
+    pub name: String,
+    pub ip: IpAddr,
+    pub sockets: Vec<SocketEntry>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct SocketEntry {
+    pub source: String,
+    pub targets: Vec<String>,
+    pub usage: Option<ResourceUsage>,
+    /// Connections being forwarded
+    #[serde(default)]
+    pub connections: usize,
+    /// Connections waiting for a slot of the connection limits
+    #[serde(default)]
+    pub queued: usize,
+}
+
+/// Capture of the connections of a plumbing
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct CaptureRequest {
+    /// pcapng file written by the daemon
+    pub path: PathBuf,
+    /// Socket to capture, all the sockets of the plumbing when missing
+    pub socket: Option<String>,
+}
+
+/// Current usage of a resource running inside a dedicated cgroup
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct ResourceUsage {
+    pub memory_bytes: Option<u64>,
+    pub cpu_usec: Option<u64>,
+    pub pids: Option<u64>,
+}
+
+impl From<PlumbingSummary> for PlumbingEntry {
+    fn from(value: PlumbingSummary) -> Self {
+        Self {
+            name: value.name,
+            ip: value.source,
+            sockets: value.sockets.into_iter()
+                .map(|socket| SocketEntry {
+                    source: socket.source,
+                    targets: socket.targets,
+                    usage: socket.usage.map(|usage| ResourceUsage {
+                        memory_bytes: usage.memory_bytes,
+                        cpu_usec: usage.cpu_usec,
+                        pids: usage.pids,
+                    }),
+                    connections: socket.connections,
+                    queued: socket.queued,
+                })
+                .collect(),
+        }
+    }
+}
+
+async fn list_endpoints(State(state): State<ApiState>) -> Json<Vec<PlumbingEntry>> {
+    let res = state.plumber.list()
+        .into_iter()
+        .map(PlumbingEntry::from)
+        .collect();
+
+    Json(res)
 }
 
 async fn resolve_endpoint(
     axum::extract::Path(name): axum::extract::Path<String>,
-    State(state): State<NameResolver>
+    State(state): State<ApiState>
 ) -> Json<Option<Endpoint>> {
-    let res = state.resolve(&name)
+    let res = state.name_resolver.resolve(&name)
         .map(|ip| Endpoint { ip });
 
     Json(res)
-}
\ No newline at end of file
+}
+
+async fn get_shaping(
+    UrlPath((name, socket)): UrlPath<(String, String)>,
+    State(state): State<ApiState>,
+) -> Result<Json<Option<ShapingConfig>>, StatusCode> {
+    let shaper = state.plumber.socket_shaper(&name, &socket)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(shaper.config()))
+}
+
+/// Only connections opened while the socket had a shaping are wrapped: replacing the shaping affects them
+/// immediately, while connections opened without shaping stay unshaped until they are closed
+async fn set_shaping(
+    UrlPath((name, socket)): UrlPath<(String, String)>,
+    State(state): State<ApiState>,
+    Json(config): Json<ShapingConfig>,
+) -> StatusCode {
+    match state.plumber.socket_shaper(&name, &socket) {
+        Some(shaper) => {
+            log::info!("Shaping {name}/{socket} - {config:?}");
+            shaper.set_config(Some(config));
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn clear_shaping(
+    UrlPath((name, socket)): UrlPath<(String, String)>,
+    State(state): State<ApiState>,
+) -> StatusCode {
+    match state.plumber.socket_shaper(&name, &socket) {
+        Some(shaper) => {
+            log::info!("Shaping of {name}/{socket} removed");
+            shaper.set_config(None);
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn get_faults(
+    UrlPath((name, socket)): UrlPath<(String, String)>,
+    State(state): State<ApiState>,
+) -> Result<Json<Option<FaultConfig>>, StatusCode> {
+    let faults = state.plumber.socket_faults(&name, &socket)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(faults.config()))
+}
+
+async fn set_faults(
+    UrlPath((name, socket)): UrlPath<(String, String)>,
+    State(state): State<ApiState>,
+    Json(config): Json<FaultConfig>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    config.validate()
+        .map_err(|err| (StatusCode::BAD_REQUEST, format!("{err:#}")))?;
+    let faults = state.plumber.socket_faults(&name, &socket)
+        .ok_or((StatusCode::NOT_FOUND, format!("No socket {socket} on {name}")))?;
+    log::info!("Injecting faults on {name}/{socket} - {config:?}");
+    faults.set_config(Some(config));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn clear_faults(
+    UrlPath((name, socket)): UrlPath<(String, String)>,
+    State(state): State<ApiState>,
+) -> StatusCode {
+    match state.plumber.socket_faults(&name, &socket) {
+        Some(faults) => {
+            log::info!("Fault injection on {name}/{socket} stopped");
+            faults.set_config(None);
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn start_capture(
+    UrlPath(name): UrlPath<String>,
+    State(state): State<ApiState>,
+    Json(request): Json<CaptureRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let switches = state.plumber.socket_captures(&name, request.socket.as_deref())
+        .ok_or((StatusCode::NOT_FOUND, format!("No socket to capture on {name}")))?;
+    let path = request.path;
+    let capture = tokio::task::spawn_blocking(move || Capture::create(&path)).await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")))?
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")))?;
+    log::info!("Capturing {name} into {:?}", capture.path());
+    for switch in switches {
+        switch.start(capture.clone());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn stop_capture(
+    UrlPath(name): UrlPath<String>,
+    State(state): State<ApiState>,
+) -> StatusCode {
+    let Some(switches) = state.plumber.socket_captures(&name, None) else {
+        return StatusCode::NOT_FOUND;
+    };
+    // every switch is stopped, not just the first one running
+    let stopped = switches.iter()
+        .filter(|switch| switch.stop())
+        .count();
+    match stopped > 0 {
+        true => {
+            log::info!("Capture of {name} stopped");
+            StatusCode::NO_CONTENT
+        }
+        false => StatusCode::NOT_FOUND,
+    }
+}
@@ -0,0 +1,166 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::{BalanceConfig, BalanceStrategy, TargetAddr};
+
+/// Distributes connections among the targets of a socket, targets failing to accept connections are
+/// kept out of rotation for `failure_timeout`
+pub struct Balancer {
+    targets: Vec<BalancedTarget>,
+    strategy: BalanceStrategy,
+    failure_timeout: Duration,
+    next: AtomicUsize,
+}
+
+pub struct BalancedTarget {
+    addr: TargetAddr,
+    connections: AtomicUsize,
+    failing_until: Mutex<Option<Instant>>,
+}
+
+/// Open connection to a target, released on drop
+pub struct Lease<'a> {
+    target: &'a BalancedTarget,
+}
+
+impl Balancer {
+    pub fn new(targets: Vec<TargetAddr>, config: &BalanceConfig) -> Self {
+        Self {
+            targets: targets.into_iter()
+                .map(|addr| BalancedTarget {
+                    addr,
+                    connections: AtomicUsize::new(0),
+                    failing_until: Mutex::new(None),
+                })
+                .collect(),
+            strategy: config.balance,
+            failure_timeout: Duration::from_millis(config.failure_timeout_millis),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn targets(&self) -> impl Iterator<Item=&TargetAddr> {
+        self.targets.iter().map(|target| &target.addr)
+    }
+
+    /// Targets in the order connections should be attempted, failing targets are only tried as a last resort
+    pub fn candidates(&self) -> Vec<&BalancedTarget> {
+        let now = Instant::now();
+        let (mut healthy, failing): (Vec<_>, Vec<_>) = self.targets.iter()
+            .partition(|target| !target.is_failing(now));
+        if self.strategy != BalanceStrategy::FirstHealthy && !healthy.is_empty() {
+            let start = self.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+            healthy.rotate_left(start);
+        }
+        if self.strategy == BalanceStrategy::LeastConnections {
+            healthy.sort_by_key(|target| target.connections.load(Ordering::Relaxed));
+        }
+        healthy.extend(failing);
+        healthy
+    }
+
+    pub fn connected<'a>(&self, target: &'a BalancedTarget) -> Lease<'a> {
+        *target.failing_until.lock().expect("Broken target mutex") = None;
+        target.connections.fetch_add(1, Ordering::Relaxed);
+        Lease { target }
+    }
+
+    pub fn failed(&self, target: &BalancedTarget) {
+        *target.failing_until.lock().expect("Broken target mutex") = Some(Instant::now() + self.failure_timeout);
+    }
+}
+
+impl BalancedTarget {
+    pub fn addr(&self) -> &TargetAddr {
+        &self.addr
+    }
+
+    fn is_failing(&self, now: Instant) -> bool {
+        self.failing_until.lock().expect("Broken target mutex")
+            .map(|until| until > now)
+            .unwrap_or(false)
+    }
+}
+
+impl Lease<'_> {
+    pub fn addr(&self) -> &TargetAddr {
+        &self.target.addr
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        self.target.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balancer(balance: BalanceStrategy) -> Balancer {
+        let targets = ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"].map(|addr| TargetAddr::Tcp(addr.parse().unwrap()));
+        Balancer::new(targets.to_vec(), &BalanceConfig { balance, failure_timeout_millis: 1000 })
+    }
+
+    fn order(balancer: &Balancer) -> Vec<String> {
+        balancer.candidates().iter().map(|target| target.addr().to_string()).collect()
+    }
+
+    #[test]
+    fn rotates_targets_in_round_robin() {
+        let balancer = balancer(BalanceStrategy::RoundRobin);
+        let first: Vec<_> = (0..4).map(|_| order(&balancer)[0].clone()).collect();
+        assert_eq!(first, ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3", "127.0.0.1:1"]);
+        assert_eq!(order(&balancer), ["127.0.0.1:2", "127.0.0.1:3", "127.0.0.1:1"]);
+    }
+
+    #[test]
+    fn prefers_targets_with_fewer_connections() {
+        let balancer = balancer(BalanceStrategy::LeastConnections);
+        let candidates = balancer.candidates();
+        let first = balancer.connected(candidates[0]);
+        let second = balancer.connected(candidates[0]);
+        let _third = balancer.connected(candidates[1]);
+        assert_eq!(order(&balancer)[0], "127.0.0.1:3");
+        drop(first);
+        drop(second);
+        // ties are broken in turn
+        assert_eq!(order(&balancer)[2], "127.0.0.1:2");
+    }
+
+    #[test]
+    fn uses_first_healthy_target() {
+        let balancer = balancer(BalanceStrategy::FirstHealthy);
+        assert_eq!(order(&balancer), ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"]);
+        assert_eq!(order(&balancer), ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"]);
+        balancer.failed(balancer.candidates()[0]);
+        assert_eq!(order(&balancer), ["127.0.0.1:2", "127.0.0.1:3", "127.0.0.1:1"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restores_failing_target_after_timeout() {
+        let balancer = balancer(BalanceStrategy::FirstHealthy);
+        balancer.failed(balancer.candidates()[0]);
+        balancer.failed(balancer.candidates()[0]);
+        // failing targets are still tried, after the healthy ones
+        assert_eq!(order(&balancer), ["127.0.0.1:3", "127.0.0.1:1", "127.0.0.1:2"]);
+
+        tokio::time::advance(Duration::from_millis(999)).await;
+        assert_eq!(order(&balancer)[0], "127.0.0.1:3");
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_eq!(order(&balancer), ["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"]);
+    }
+
+    #[test]
+    fn successful_connection_clears_failure() {
+        let balancer = balancer(BalanceStrategy::FirstHealthy);
+        let target = balancer.candidates()[0];
+        balancer.failed(target);
+        drop(balancer.connected(target));
+        assert_eq!(order(&balancer)[0], "127.0.0.1:1");
+    }
+}
@@ -15,5 +15,59 @@ pub struct PluCtlArgs {
 pub enum Commands {
     /// List current mappings
     List,
-    Resolve { name: String }
+    Resolve { name: String },
+    /// Inject failures on a socket
+    Fault {
+        #[clap(subcommand)]
+        command: FaultCommand,
+    },
+    /// Record the connections of a plumbing into a pcapng file
+    Capture {
+        #[clap(subcommand)]
+        command: CaptureCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CaptureCommand {
+    /// Capture the connections opened from now on
+    Start {
+        name: String,
+        /// Capture a single socket of the plumbing
+        #[arg(long)]
+        socket: Option<String>,
+        /// New file written by port-plumber, defaults to <name>.pcapng in the current directory
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Stop capturing and close the file
+    Stop { name: String },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum FaultCommand {
+    /// Show the faults injected on a socket
+    Show { name: String, socket: String },
+    /// Replace the faults injected on the new connections of a socket
+    Set {
+        name: String,
+        socket: String,
+        /// Share of the connections reset as soon as they are accepted, in percent
+        #[arg(long, default_value_t = 0.0)]
+        refuse_percent: f64,
+        /// Reset the connections after this many bytes
+        #[arg(long)]
+        reset_after_bytes: Option<u64>,
+        /// Reset the connections after a random duration up to this value
+        #[arg(long)]
+        reset_after_millis: Option<u64>,
+        /// Discard the data of the clients without contacting the target
+        #[arg(long)]
+        blackhole: bool,
+        /// Probability of each forwarded byte to be corrupted
+        #[arg(long, default_value_t = 0.0)]
+        corrupt_rate: f64,
+    },
+    /// Stop injecting faults on a socket
+    Clear { name: String, socket: String },
 }
\ No newline at end of file
@@ -4,6 +4,7 @@ use hyper::client::connect::Connect;
 use std::error::Error as StdError;
 use anyhow::bail;
 use serde::de::DeserializeOwned;
+use serde::Serialize;
 
 pub struct SimpleRest<C, B = Body> {
     client: hyper::Client<C, B>
@@ -38,4 +39,39 @@ impl <C, B> SimpleRest<C, B>
         let parsed_res = serde_json::from_slice(&res_body[..])?;
         Ok(parsed_res)
     }
-}
\ No newline at end of file
+}
+
+impl <C> SimpleRest<C, Body>
+    where
+        C: Connect + Clone + Send + Sync + 'static,
+{
+    pub async fn put<U, Req>(&self, url: U, body: &Req) -> anyhow::Result<()>
+    where
+        U: Into<Uri>,
+        Req: Serialize,
+    {
+        let req = hyper::Request::put(url.into())
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(body)?))?;
+        self.send(req).await
+    }
+
+    pub async fn delete<U: Into<Uri>>(&self, url: U) -> anyhow::Result<()> {
+        let req = hyper::Request::delete(url.into())
+            .body(Body::empty())?;
+        self.send(req).await
+    }
+
+    async fn send(&self, req: hyper::Request<Body>) -> anyhow::Result<()> {
+        let res = self.client.request(req).await?;
+        let status = res.status();
+        if !status.is_success() {
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+            match String::from_utf8_lossy(&body).trim() {
+                "" => bail!("Response error {status}"),
+                message => bail!("Response error {status} - {message}"),
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,8 +1,9 @@
 use clap::Parser;
 use hyper::Client;
 use hyperlocal::{UnixClientExt, Uri};
-use port_plumber::api::Endpoint;
-use crate::args::{Commands, PluCtlArgs};
+use port_plumber::api::{CaptureRequest, Endpoint, PlumbingEntry};
+use port_plumber::config::FaultConfig;
+use crate::args::{CaptureCommand, Commands, FaultCommand, PluCtlArgs};
 use crate::client::SimpleRest;
 
 mod args;
@@ -15,8 +16,16 @@ async fn main() -> anyhow::Result<()> {
     let client = SimpleRest::from(Client::unix());
     match args.subcommand {
         Commands::List => {
-            let res: Vec<Endpoint> = client.get(Uri::new(args.path, "/list")).await?;
-            println!("{res:?}");
+            let res: Vec<PlumbingEntry> = client.get(Uri::new(args.path, "/list")).await?;
+            for entry in res {
+                for socket in entry.sockets {
+                    print!("{} {} -> {} ({} connections, {} queued)", entry.name, socket.source, socket.targets.join(", "), socket.connections, socket.queued);
+                    if let Some(usage) = socket.usage {
+                        print!(" {usage:?}");
+                    }
+                    println!();
+                }
+            }
         },
         Commands::Resolve { name } => {
             let opt_res: Option<Endpoint> = client.get(Uri::new(args.path, &format!("/resolve/{name}"))).await?;
@@ -24,6 +33,35 @@ async fn main() -> anyhow::Result<()> {
                 println!("{}", res.ip);
             }
         }
+        Commands::Fault { command } => match command {
+            FaultCommand::Show { name, socket } => {
+                let faults: Option<FaultConfig> = client.get(Uri::new(args.path, &format!("/faults/{name}/{socket}"))).await?;
+                match faults {
+                    Some(faults) => println!("{faults:?}"),
+                    None => println!("No faults injected"),
+                }
+            }
+            FaultCommand::Set { name, socket, refuse_percent, reset_after_bytes, reset_after_millis, blackhole, corrupt_rate } => {
+                let faults = FaultConfig { refuse_percent, reset_after_bytes, reset_after_millis, blackhole, corrupt_rate };
+                faults.validate()?;
+                client.put(Uri::new(args.path, &format!("/faults/{name}/{socket}")), &faults).await?;
+            }
+            FaultCommand::Clear { name, socket } => {
+                client.delete(Uri::new(args.path, &format!("/faults/{name}/{socket}"))).await?;
+            }
+        },
+        Commands::Capture { command } => match command {
+            CaptureCommand::Start { name, socket, output } => {
+                // the file is written by the daemon, relative paths are resolved here
+                let path = std::env::current_dir()?
+                    .join(output.unwrap_or_else(|| format!("{name}.pcapng").into()));
+                client.put(Uri::new(args.path, &format!("/capture/{name}")), &CaptureRequest { path: path.clone(), socket }).await?;
+                println!("Capturing {name} into {}", path.display());
+            }
+            CaptureCommand::Stop { name } => {
+                client.delete(Uri::new(args.path, &format!("/capture/{name}"))).await?;
+            }
+        },
     }
     Ok(())
 }
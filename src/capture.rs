@@ -0,0 +1,400 @@
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context as _;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::shaping::Direction;
+
+/// Raw IPv4/IPv6 packets, without link layer header
+const LINKTYPE_RAW: u16 = 101;
+/// Largest payload of a synthesized segment, keeps the IP packets under 64KB
+const MAX_SEGMENT: usize = 65_000;
+/// Packets waiting for the writer thread, packets are dropped rather than stalling the connections
+const QUEUE_LENGTH: usize = 4096;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+/// Capture toggled at runtime on the connections of a socket
+pub struct CaptureSwitch {
+    capture: Mutex<Option<Arc<Capture>>>,
+}
+
+/// pcapng file receiving the packets of the captured connections
+pub struct Capture {
+    path: PathBuf,
+    /// Closed when the capture is stopped, the file is flushed once the writer drains the queue
+    sender: Mutex<Option<mpsc::SyncSender<Vec<u8>>>>,
+    /// Packets dropped because the writer fell behind
+    dropped: Arc<AtomicU64>,
+}
+
+/// TCP session synthesized from the payloads forwarded on a connection
+pub struct CapturedConnection {
+    capture: Arc<Capture>,
+    client: SocketAddr,
+    server: SocketAddr,
+    sequences: Mutex<Sequences>,
+}
+
+/// Next sequence number of each peer
+struct Sequences {
+    client: u32,
+    server: u32,
+}
+
+/// Stream recording the data read from it into the capture of its connection
+pub struct CapturedStream<S> {
+    inner: S,
+    connection: Arc<CapturedConnection>,
+    direction: Direction,
+}
+
+impl CaptureSwitch {
+    pub fn new() -> Self {
+        Self { capture: Mutex::new(None) }
+    }
+
+    pub fn current(&self) -> Option<Arc<Capture>> {
+        self.capture.lock().expect("Broken capture mutex").clone()
+    }
+
+    /// Captures the connections accepted from now on, replacing the current capture
+    pub fn start(&self, capture: Arc<Capture>) {
+        *self.capture.lock().expect("Broken capture mutex") = Some(capture);
+    }
+
+    /// Stops the current capture, returns false when none was running
+    pub fn stop(&self) -> bool {
+        match self.capture.lock().expect("Broken capture mutex").take() {
+            Some(capture) => {
+                capture.close();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Capture {
+    /// Creates the file at `path`, which must not exist, and starts the thread writing into it.
+    /// The daemon may run as root: an existing file is never overwritten.
+    pub fn create(path: &Path) -> anyhow::Result<Arc<Self>> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)
+            .with_context(|| format!("Error creating capture file {path:?}"))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&section_header())?;
+        writer.write_all(&interface_description())?;
+
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(QUEUE_LENGTH);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let thread_dropped = dropped.clone();
+        let thread_path = path.to_path_buf();
+        std::thread::Builder::new()
+            .name("capture".to_string())
+            .spawn(move || {
+                let res = receiver.iter()
+                    .try_for_each(|block| writer.write_all(&block))
+                    .and_then(|_| writer.flush());
+                match res {
+                    Ok(()) => match thread_dropped.load(Ordering::Relaxed) {
+                        0 => log::info!("Capture {thread_path:?} completed"),
+                        dropped => log::warn!("Capture {thread_path:?} completed, {dropped} packets dropped"),
+                    },
+                    Err(err) => log::error!("Error writing capture {thread_path:?} - {err}"),
+                }
+            })?;
+        Ok(Arc::new(Self { path: path.to_path_buf(), sender: Mutex::new(Some(sender)), dropped }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Starts capturing a connection between `client` and `server`, the handshake is synthesized immediately
+    pub fn connection(self: &Arc<Self>, client: SocketAddr, server: SocketAddr) -> Arc<CapturedConnection> {
+        let (client, server) = match (client.ip(), server.ip()) {
+            (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => (client, server),
+            _ => (to_ipv6(client), to_ipv6(server)),
+        };
+        let connection = CapturedConnection {
+            capture: self.clone(),
+            client,
+            server,
+            sequences: Mutex::new(Sequences { client: fastrand::u32(..), server: fastrand::u32(..) }),
+        };
+        {
+            let mut seq = connection.sequences.lock().expect("Broken capture mutex");
+            connection.emit(Direction::Upstream, seq.client, 0, TCP_SYN, &[]);
+            seq.client = seq.client.wrapping_add(1);
+            connection.emit(Direction::Downstream, seq.server, seq.client, TCP_SYN | TCP_ACK, &[]);
+            seq.server = seq.server.wrapping_add(1);
+            connection.emit(Direction::Upstream, seq.client, seq.server, TCP_ACK, &[]);
+        }
+        Arc::new(connection)
+    }
+
+    fn send(&self, block: Vec<u8>) {
+        if let Some(sender) = &*self.sender.lock().expect("Broken capture mutex") {
+            match sender.try_send(block) {
+                Ok(()) => {}
+                Err(mpsc::TrySendError::Full(_)) => { self.dropped.fetch_add(1, Ordering::Relaxed); }
+                // the writer thread only stops after an error, already logged
+                Err(mpsc::TrySendError::Disconnected(_)) => {}
+            }
+        }
+    }
+
+    fn close(&self) {
+        self.sender.lock().expect("Broken capture mutex").take();
+    }
+}
+
+impl CapturedConnection {
+    /// Wraps a stream of the connection, `direction` is the flow of the data read from it
+    pub fn stream<S>(self: &Arc<Self>, inner: S, direction: Direction) -> CapturedStream<S> {
+        CapturedStream { inner, connection: self.clone(), direction }
+    }
+
+    fn record(&self, direction: Direction, data: &[u8]) {
+        let mut seq = self.sequences.lock().expect("Broken capture mutex");
+        for segment in data.chunks(MAX_SEGMENT) {
+            match direction {
+                Direction::Upstream => {
+                    self.emit(direction, seq.client, seq.server, TCP_PSH | TCP_ACK, segment);
+                    seq.client = seq.client.wrapping_add(segment.len() as u32);
+                }
+                Direction::Downstream => {
+                    self.emit(direction, seq.server, seq.client, TCP_PSH | TCP_ACK, segment);
+                    seq.server = seq.server.wrapping_add(segment.len() as u32);
+                }
+            }
+        }
+    }
+
+    fn emit(&self, direction: Direction, seq: u32, ack: u32, flags: u8, payload: &[u8]) {
+        let (source, destination) = match direction {
+            Direction::Upstream => (self.client, self.server),
+            Direction::Downstream => (self.server, self.client),
+        };
+        let packet = tcp_packet(source, destination, seq, ack, flags, payload);
+        self.capture.send(enhanced_packet(&packet));
+    }
+}
+
+impl Drop for CapturedConnection {
+    fn drop(&mut self) {
+        let seq = self.sequences.get_mut().expect("Broken capture mutex");
+        let (client, server) = (seq.client, seq.server);
+        self.emit(Direction::Upstream, client, server, TCP_FIN | TCP_ACK, &[]);
+        self.emit(Direction::Downstream, server, client.wrapping_add(1), TCP_FIN | TCP_ACK, &[]);
+        self.emit(Direction::Upstream, client.wrapping_add(1), server.wrapping_add(1), TCP_ACK, &[]);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CapturedStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = &buf.filled()[before..];
+        if !read.is_empty() {
+            self.connection.record(self.direction, read);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CapturedStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+fn section_header() -> Vec<u8> {
+    let mut body = Vec::with_capacity(16);
+    body.extend_from_slice(&0x1A2B3C4Du32.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // section length not specified
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    block(0x0A0D0D0A, &body)
+}
+
+fn interface_description() -> Vec<u8> {
+    let mut body = Vec::with_capacity(8);
+    body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // no snapshot length limit
+    body.extend_from_slice(&0u32.to_le_bytes());
+    block(0x00000001, &body)
+}
+
+fn enhanced_packet(packet: &[u8]) -> Vec<u8> {
+    // default resolution of the timestamps is microseconds
+    let micros = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+    let mut body = Vec::with_capacity(20 + packet.len() + 3);
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(packet);
+    body.resize(body.len().next_multiple_of(4), 0);
+    block(0x00000006, &body)
+}
+
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let total_length = (body.len() + 12) as u32;
+    let mut block = Vec::with_capacity(total_length as usize);
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&total_length.to_le_bytes());
+    block.extend_from_slice(body);
+    block.extend_from_slice(&total_length.to_le_bytes());
+    block
+}
+
+/// Builds an IP packet carrying a TCP segment, with valid checksums
+fn tcp_packet(source: SocketAddr, destination: SocketAddr, seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut segment = Vec::with_capacity(20 + payload.len());
+    segment.extend_from_slice(&source.port().to_be_bytes());
+    segment.extend_from_slice(&destination.port().to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.push(5 << 4);
+    segment.push(flags);
+    segment.extend_from_slice(&u16::MAX.to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    segment.extend_from_slice(payload);
+
+    let mut packet = Vec::with_capacity(40 + segment.len());
+    let mut pseudo_header = Vec::with_capacity(36);
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((20 + segment.len()) as u16).to_be_bytes());
+            // identification, flags (don't fragment), fragment offset, ttl, protocol
+            packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let checksum = checksum(&packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            pseudo_header.extend_from_slice(&src.octets());
+            pseudo_header.extend_from_slice(&dst.octets());
+            pseudo_header.extend_from_slice(&[0, 6]);
+            pseudo_header.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+        }
+        (src, dst) => {
+            let src = match src { IpAddr::V4(ip) => ip.to_ipv6_mapped(), IpAddr::V6(ip) => ip };
+            let dst = match dst { IpAddr::V4(ip) => ip.to_ipv6_mapped(), IpAddr::V6(ip) => ip };
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+            // next header, hop limit
+            packet.extend_from_slice(&[6, 64]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+
+            pseudo_header.extend_from_slice(&src.octets());
+            pseudo_header.extend_from_slice(&dst.octets());
+            pseudo_header.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, 6]);
+        }
+    }
+    let checksum = checksum(&[pseudo_header.as_slice(), segment.as_slice()].concat());
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&segment);
+    packet
+}
+
+/// Internet checksum (RFC 1071)
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data.chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)])))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_internet_checksum() {
+        // RFC 1071 example and the IPv4 header of the checksum article of Wikipedia
+        assert_eq!(checksum(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]), !0xddf2);
+        let header = [0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7];
+        assert_eq!(checksum(&header), 0xb861);
+        // odd lengths are padded with a zero byte
+        assert_eq!(checksum(&[0x12, 0x34, 0x56]), checksum(&[0x12, 0x34, 0x56, 0x00]));
+    }
+
+    #[test]
+    fn builds_ipv4_segments() {
+        let packet = tcp_packet("10.0.0.1:5000".parse().unwrap(), "10.0.0.2:80".parse().unwrap(), 1, 2, TCP_PSH | TCP_ACK, b"hello");
+        assert_eq!(packet.len(), 20 + 20 + 5);
+        assert_eq!(packet[0], 0x45);
+        assert_eq!(u16::from_be_bytes([packet[2], packet[3]]), 45);
+        assert_eq!(checksum(&packet[..20]), 0, "IP header checksum");
+
+        let segment = &packet[20..];
+        assert_eq!(u16::from_be_bytes([segment[0], segment[1]]), 5000);
+        assert_eq!(u16::from_be_bytes([segment[2], segment[3]]), 80);
+        assert_eq!(u32::from_be_bytes([segment[4], segment[5], segment[6], segment[7]]), 1);
+        assert_eq!(u32::from_be_bytes([segment[8], segment[9], segment[10], segment[11]]), 2);
+        assert_eq!(segment[13], TCP_PSH | TCP_ACK);
+        assert_eq!(&segment[20..], b"hello");
+        let pseudo_header = [&packet[12..20], &[0, 6, 0, segment.len() as u8]].concat();
+        assert_eq!(checksum(&[pseudo_header.as_slice(), segment].concat()), 0, "TCP checksum");
+    }
+
+    #[test]
+    fn builds_ipv6_segments() {
+        let packet = tcp_packet("[2001:db8::1]:5000".parse().unwrap(), "[::1]:80".parse().unwrap(), 1, 0, TCP_SYN, &[]);
+        assert_eq!(packet.len(), 40 + 20);
+        assert_eq!(packet[0] >> 4, 6);
+        assert_eq!(u16::from_be_bytes([packet[4], packet[5]]), 20);
+        assert_eq!(packet[6], 6);
+
+        let segment = &packet[40..];
+        let pseudo_header = [&packet[8..40], &[0, 0, 0, 20, 0, 0, 0, 6]].concat();
+        assert_eq!(checksum(&[pseudo_header.as_slice(), segment].concat()), 0, "TCP checksum");
+    }
+
+    #[test]
+    fn pads_blocks_to_32_bits() {
+        let block = enhanced_packet(&[1, 2, 3]);
+        assert_eq!(block.len() % 4, 0);
+        let total_length = u32::from_le_bytes(block[4..8].try_into().unwrap());
+        assert_eq!(total_length as usize, block.len());
+        assert_eq!(block[block.len() - 4..], total_length.to_le_bytes());
+    }
+}
@@ -0,0 +1,160 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{bail, Context};
+
+use crate::config::ResourceConfig;
+
+const CGROUP_MOUNT: &str = "/sys/fs/cgroup";
+const DAEMON_LEAF: &str = "daemon";
+const CONTROLLERS: [&str; 3] = ["memory", "cpu", "pids"];
+const CPU_PERIOD_USEC: u64 = 100_000;
+
+static DELEGATED_ROOT: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Cgroup v2 node holding the processes of a single resource
+pub struct ResourceCgroup {
+    path: PathBuf,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CgroupUsage {
+    pub memory_bytes: Option<u64>,
+    pub cpu_usec: Option<u64>,
+    pub pids: Option<u64>,
+}
+
+impl ResourceCgroup {
+    /// Creates the cgroup for the given resource if any limit is configured.
+    /// When the daemon cgroup subtree is not delegated a warning is logged and no limit is applied.
+    pub fn for_resource(name: &str, cfg: &ResourceConfig) -> Option<Self> {
+        if cfg.memory_max.is_none() && cfg.cpu_max.is_none() && cfg.pids_max.is_none() {
+            return None;
+        }
+        let root = DELEGATED_ROOT.get_or_init(|| match prepare_delegated_root() {
+            Ok(root) => Some(root),
+            Err(err) => {
+                log::warn!("Cgroup delegation not available, resource limits will be ignored - {err:#}");
+                None
+            }
+        }).as_ref()?;
+
+        match Self::create(root, name, cfg) {
+            Ok(cgroup) => Some(cgroup),
+            Err(err) => {
+                log::warn!("Could not create cgroup for resource {name}, limits will be ignored - {err:#}");
+                None
+            }
+        }
+    }
+
+    fn create(root: &Path, name: &str, cfg: &ResourceConfig) -> anyhow::Result<Self> {
+        let path = root.join(format!("resource-{}", sanitize(name)));
+        match fs::create_dir(&path) {
+            Err(err) if err.kind() != ErrorKind::AlreadyExists => {
+                return Err(err).with_context(|| format!("Error creating cgroup {path:?}"));
+            }
+            _ => {}
+        }
+        let cgroup = Self { path };
+        if let Some(ref memory_max) = cfg.memory_max {
+            cgroup.write("memory.max", memory_max)?;
+        }
+        if let Some(cpu_max) = cfg.cpu_max {
+            let quota = (cpu_max * CPU_PERIOD_USEC as f64) as u64;
+            cgroup.write("cpu.max", &format!("{quota} {CPU_PERIOD_USEC}"))?;
+        }
+        if let Some(pids_max) = cfg.pids_max {
+            cgroup.write("pids.max", &pids_max.to_string())?;
+        }
+        Ok(cgroup)
+    }
+
+    /// Path of the `cgroup.procs` file, a process writing its pid here is moved into the cgroup
+    pub fn procs_path(&self) -> PathBuf {
+        self.path.join("cgroup.procs")
+    }
+
+    /// Kills every process still living in the cgroup (requires linux 5.14)
+    pub fn kill(&self) -> anyhow::Result<()> {
+        self.write("cgroup.kill", "1")
+    }
+
+    pub fn usage(&self) -> CgroupUsage {
+        CgroupUsage {
+            memory_bytes: self.read("memory.current").and_then(|v| v.trim().parse().ok()),
+            cpu_usec: self.read("cpu.stat").and_then(|stat| stat.lines()
+                .find_map(|line| line.strip_prefix("usage_usec "))
+                .and_then(|v| v.trim().parse().ok())),
+            pids: self.read("pids.current").and_then(|v| v.trim().parse().ok()),
+        }
+    }
+
+    fn read(&self, file: &str) -> Option<String> {
+        fs::read_to_string(self.path.join(file)).ok()
+    }
+
+    fn write(&self, file: &str, value: &str) -> anyhow::Result<()> {
+        fs::write(self.path.join(file), value)
+            .with_context(|| format!("Error writing {value} to {:?}", self.path.join(file)))
+    }
+}
+
+impl Drop for ResourceCgroup {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir(&self.path) {
+            log::debug!("Could not remove cgroup {:?} - {err}", self.path);
+        }
+    }
+}
+
+/// Moves the daemon into a leaf of its own cgroup so that controllers can be enabled for the resource cgroups
+fn prepare_delegated_root() -> anyhow::Result<PathBuf> {
+    let proc_cgroup = fs::read_to_string("/proc/self/cgroup")
+        .context("Error reading /proc/self/cgroup")?;
+    let Some(own_path) = proc_cgroup.lines().find_map(|line| line.strip_prefix("0::")) else {
+        bail!("cgroup v2 unified hierarchy not found")
+    };
+    let own_cgroup = Path::new(CGROUP_MOUNT).join(own_path.trim_start_matches('/'));
+    // after a restart in the same cgroup the daemon may already be in its leaf
+    let root = match (own_cgroup.file_name(), own_cgroup.parent()) {
+        (Some(leaf_name), Some(parent)) if leaf_name == DAEMON_LEAF => parent.to_path_buf(),
+        _ => own_cgroup.clone(),
+    };
+
+    let leaf = root.join(DAEMON_LEAF);
+    if own_cgroup != leaf {
+        match fs::create_dir(&leaf) {
+            Err(err) if err.kind() != ErrorKind::AlreadyExists => {
+                return Err(err).with_context(|| format!("Error creating cgroup {leaf:?}"));
+            }
+            _ => {}
+        }
+        let procs = fs::read_to_string(root.join("cgroup.procs"))
+            .with_context(|| format!("Error reading processes of {root:?}"))?;
+        for pid in procs.lines() {
+            fs::write(leaf.join("cgroup.procs"), pid)
+                .with_context(|| format!("Error moving process {pid} to {leaf:?}"))?;
+        }
+    }
+
+    let available = fs::read_to_string(root.join("cgroup.controllers"))
+        .with_context(|| format!("Error reading controllers of {root:?}"))?;
+    for controller in CONTROLLERS {
+        if !available.split_whitespace().any(|c| c == controller) {
+            log::warn!("Cgroup controller {controller} is not delegated to {root:?}");
+            continue;
+        }
+        fs::write(root.join("cgroup.subtree_control"), format!("+{controller}"))
+            .with_context(|| format!("Error enabling controller {controller} in {root:?}"))?;
+    }
+    Ok(root)
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+        .collect()
+}
@@ -1,18 +1,40 @@
+use std::sync::Arc;
 use std::time::Duration;
 
+use tokio::sync::{watch, Mutex};
+
+use crate::cgroup::ResourceCgroup;
 use crate::config::ResourceConfig;
+use crate::credentials::Credentials;
 use crate::healthcheck::HealthcheckCommand;
 use crate::runner::CmdRunner;
 
+#[allow(clippy::large_enum_variant)]
 pub enum CmdResource {
     Empty,
     Command {
         runner: CmdRunner,
         warmup: Duration,
         healthcheck: Option<HealthcheckCommand>,
+        cgroup: Option<Arc<ResourceCgroup>>,
     }
 }
 
+/// Resource shared among the connections of a socket, its status can be observed while it starts
+#[derive(Clone)]
+pub struct SharedResource {
+    resource: Arc<Mutex<CmdResource>>,
+    status: Arc<watch::Sender<ResourceStatus>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceStatus {
+    Stopped,
+    /// Spawned and waiting for warmup or healthcheck
+    Starting,
+    Running,
+}
+
 impl TryFrom<Option<&ResourceConfig>> for CmdResource {
     type Error = anyhow::Error;
 
@@ -20,18 +42,35 @@ impl TryFrom<Option<&ResourceConfig>> for CmdResource {
         let Some(cfg) = value else {
             return Ok(Self::Empty)
         };
+        let mut runner = CmdRunner::build(&cfg.setup.command, &cfg.setup.args, &cfg.setup.workingdir)?;
+        if let Some(credentials) = Credentials::from_config(&cfg.setup)? {
+            runner.run_as(credentials);
+        }
         Ok(Self::Command {
-            runner: CmdRunner::build(&cfg.setup.command, &cfg.setup.args, &cfg.setup.workingdir)?,
+            runner,
             warmup: Duration::from_millis(cfg.warmup_millis),
             healthcheck: cfg.healthcheck_cmd.clone().and_then(|conf| HealthcheckCommand::new(conf).ok()),
+            cgroup: None,
         })
     }
 }
 
 impl CmdResource {
-    pub async fn ensure_running(&mut self) -> anyhow::Result<()> {
-        let Self::Command { runner, warmup, healthcheck } = self else {
-            return Ok(())
+    /// Spawns the resource processes inside the given cgroup
+    pub fn in_cgroup(mut self, resource_cgroup: Option<Arc<ResourceCgroup>>) -> anyhow::Result<Self> {
+        if let Self::Command { runner, cgroup, .. } = &mut self {
+            if let Some(ref resource_cgroup) = resource_cgroup {
+                runner.join_cgroup(&resource_cgroup.procs_path())?;
+            }
+            *cgroup = resource_cgroup;
+        }
+        Ok(self)
+    }
+
+    /// Starts the command when not running, returns whether it was started
+    pub async fn ensure_running(&mut self) -> anyhow::Result<bool> {
+        let Self::Command { runner, warmup, healthcheck, .. } = self else {
+            return Ok(false)
         };
         if !runner.is_running()? {
             log::debug!("spawning command");
@@ -43,21 +82,70 @@ impl CmdResource {
                     log::error!("Error waiting process startup - {err}");
                 }
             }
-            Ok(())
+            Ok(true)
         } else {
-            Ok(())
+            Ok(false)
         }
     }
 
     pub fn ensure_stopped(&mut self) -> anyhow::Result<()> {
-        let Self::Command { runner, .. } = self else {
+        let Self::Command { runner, cgroup, .. } = self else {
             return Ok(())
         };
         if runner.is_running()? {
             log::debug!("stopping command");
-            runner.stop()
+            runner.stop()?;
+            if let Some(cgroup) = cgroup {
+                if let Err(err) = cgroup.kill() {
+                    log::warn!("Error killing remaining resource processes - {err}");
+                }
+            }
+            Ok(())
         } else {
             Ok(())
         }
     }
-}
\ No newline at end of file
+}
+
+impl SharedResource {
+    pub fn new(resource: CmdResource) -> Self {
+        let status = match resource {
+            CmdResource::Empty => ResourceStatus::Running,
+            CmdResource::Command { .. } => ResourceStatus::Stopped,
+        };
+        Self {
+            resource: Arc::new(Mutex::new(resource)),
+            status: Arc::new(watch::channel(status).0),
+        }
+    }
+
+    pub fn status(&self) -> ResourceStatus {
+        *self.status.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<ResourceStatus> {
+        self.status.subscribe()
+    }
+
+    /// Starts the resource when not running, concurrent callers wait for the same startup; returns whether this call started it
+    pub async fn ensure_running(&self) -> anyhow::Result<bool> {
+        let mut resource = self.resource.lock().await;
+        if let CmdResource::Command { runner, .. } = &mut *resource {
+            if !runner.is_running()? {
+                self.status.send_replace(ResourceStatus::Starting);
+            }
+        }
+        let out = resource.ensure_running().await;
+        self.status.send_replace(if out.is_ok() { ResourceStatus::Running } else { ResourceStatus::Stopped });
+        out
+    }
+
+    pub async fn ensure_stopped(&self) -> anyhow::Result<()> {
+        let mut resource = self.resource.lock().await;
+        let out = resource.ensure_stopped();
+        if let CmdResource::Command { .. } = &*resource {
+            self.status.send_replace(ResourceStatus::Stopped);
+        }
+        out
+    }
+}
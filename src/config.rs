@@ -1,35 +1,298 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::BTreeMap;
 use std::convert::Infallible;
-use std::net::{IpAddr, SocketAddr};
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 use std::str::FromStr;
+use anyhow::anyhow;
 use handlebars::Handlebars;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde::de::Visitor;
 use crate::utils::serde::string_or_struct;
 
 #[derive(Deserialize)]
 pub struct PortPlumberConfig {
     pub socket: Option<PathBuf>,
+    /// Unprivileged identity the daemon switches to once listeners are bound
+    #[serde(default)]
+    pub run_as: Option<RunAsConfig>,
+    /// Time given to in-flight connections to complete when the daemon is stopped
+    #[serde(default = "default_shutdown_timeout_millis")]
+    pub shutdown_timeout_millis: u64,
+    /// Directory of the CA issuing certificates for TLS listeners without a configured certificate
+    #[serde(default)]
+    pub tls_ca_dir: Option<PathBuf>,
     pub plumbing: BTreeMap<String, PlumbingItemConfig>,
 }
 
+fn default_shutdown_timeout_millis() -> u64 {
+    10_000
+}
+
+#[derive(Deserialize)]
+pub struct RunAsConfig {
+    pub user: String,
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
 #[derive(Deserialize)]
 #[serde(tag = "mode")]
 pub enum PlumbingItemConfig {
     Addr(SocketConf<AddrPlumbingConfig>),
     Name(SocketConf<NamePlumbingConfig>),
+    Sni(SocketConf<SniPlumbingConfig>),
+    Http(SocketConf<HttpPlumbingConfig>),
+    Socks(SocketConf<SocksPlumbingConfig>),
+    HttpProxy(SocketConf<HttpProxyPlumbingConfig>),
 }
 
 #[derive(Deserialize, Clone)]
 pub struct SocketConf<T> {
-    pub sockets: BTreeMap<String, T>
+    pub sockets: BTreeMap<String, T>,
+    /// Log of the connections forwarded by the sockets of the plumbing
+    #[serde(default)]
+    pub access_log: Option<AccessLogConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AccessLogConfig {
+    /// File the entries are appended to, standard output when missing
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Handlebars template of an entry, JSON lines when missing
+    #[serde(default)]
+    pub format: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct AddrPlumbingConfig {
-    pub source: u16,
-    pub target: SocketAddr,
+    pub source: SourceAddr,
+    /// Single forwarding target, shorthand for a one element `targets` list
+    #[serde(default)]
+    pub target: Option<TargetSpec>,
+    /// Forwarding targets, connections are distributed among them according to `balance`
+    #[serde(default)]
+    pub targets: Vec<TargetSpec>,
+    #[serde(flatten)]
+    pub balance: BalanceConfig,
     pub resource: Option<ResourceConfig>,
+    /// Permissions of the socket file when listening on a unix socket
+    #[serde(default)]
+    pub socket_mode: Option<u32>,
+    #[serde(flatten)]
+    pub connection: ConnectionConfig,
+}
+
+impl AddrPlumbingConfig {
+    /// Targets of each listener of the source, one per port of a port range, shifted by the offset of the port.
+    /// Target port ranges must be as long as the source range.
+    pub fn listener_targets(&self) -> anyhow::Result<Vec<Vec<TargetAddr>>> {
+        let source_ports = self.source.ports();
+        let specs = self.target.iter().chain(self.targets.iter()).collect::<Vec<_>>();
+        for spec in &specs {
+            match (&spec.addr, spec.last_port, &source_ports) {
+                (_, None, _) => {}
+                (TargetAddr::Tcp(addr), Some(last_port), Some(ports))
+                    if last_port - addr.port() == ports.end() - ports.start() => {}
+                _ => return Err(anyhow!("Target {spec} does not match source {}", self.source)),
+            }
+        }
+        let listeners = match &self.source {
+            SourceAddr::Range(first, last) => usize::from(last - first) + 1,
+            SourceAddr::Port(_) | SourceAddr::Unix(_) => 1,
+        };
+        (0..listeners)
+            .map(|offset| specs.iter().map(|spec| spec.shifted(offset)).collect())
+            .collect()
+    }
+}
+
+/// Listening address, either a TCP port, a range of TCP ports (`30000-30100`) or a unix socket path
+/// (`unix:/path` or an absolute path)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceAddr {
+    Port(u16),
+    Range(u16, u16),
+    Unix(PathBuf),
+}
+
+impl SourceAddr {
+    /// TCP ports listened on, `None` for unix sockets
+    pub fn ports(&self) -> Option<RangeInclusive<u16>> {
+        match self {
+            SourceAddr::Port(port) => Some(*port..=*port),
+            SourceAddr::Range(first, last) => Some(*first..=*last),
+            SourceAddr::Unix(_) => None,
+        }
+    }
+}
+
+/// Target of an `Addr` socket, TCP targets of a port range source are shifted by the same offset as the
+/// source port, a matching range can be given explicitly (`127.0.0.1:40000-40100`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetSpec {
+    pub addr: TargetAddr,
+    /// Last port of the target range, the first one being the port of `addr`
+    pub last_port: Option<u16>,
+}
+
+/// Forwarding target, either a TCP socket address or a unix socket path (`unix:/path` or an absolute path)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+fn parse_unix_path(s: &str) -> Option<PathBuf> {
+    s.strip_prefix("unix:")
+        .or_else(|| s.starts_with('/').then_some(s))
+        .map(PathBuf::from)
+}
+
+impl FromStr for SourceAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = parse_unix_path(s) {
+            return Ok(Self::Unix(path));
+        }
+        match parse_port_range(s)? {
+            Some((first, last)) => Ok(Self::Range(first, last)),
+            None => Ok(Self::Port(s.parse()?)),
+        }
+    }
+}
+
+/// Parses `first-last`, `None` is returned when `s` is not a range
+fn parse_port_range(s: &str) -> anyhow::Result<Option<(u16, u16)>> {
+    let Some((first, last)) = s.split_once('-') else {
+        return Ok(None);
+    };
+    let (first, last) = (first.trim().parse()?, last.trim().parse()?);
+    if first > last {
+        return Err(anyhow!("Invalid port range {s}"));
+    }
+    Ok(Some((first, last)))
+}
+
+impl FromStr for TargetSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if parse_unix_path(s).is_none() {
+            if let Some((host, ports)) = s.rsplit_once(':') {
+                if let Some((first, last)) = parse_port_range(ports)? {
+                    return Ok(Self { addr: format!("{host}:{first}").parse()?, last_port: Some(last) });
+                }
+            }
+        }
+        Ok(Self { addr: s.parse()?, last_port: None })
+    }
+}
+
+impl TargetSpec {
+    /// Target of the listener `offset` ports after the first port of the source
+    fn shifted(&self, offset: usize) -> anyhow::Result<TargetAddr> {
+        match &self.addr {
+            _ if offset == 0 => Ok(self.addr.clone()),
+            TargetAddr::Tcp(addr) => u16::try_from(offset).ok()
+                .and_then(|offset| addr.port().checked_add(offset))
+                .map(|port| TargetAddr::Tcp(SocketAddr::new(addr.ip(), port)))
+                .ok_or_else(|| anyhow!("Target port range of {addr} exceeds the port numbers")),
+            TargetAddr::Unix(path) => Err(anyhow!("Unix socket {path:?} cannot be the target of a port range")),
+        }
+    }
+}
+
+impl Display for TargetSpec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.last_port {
+            Some(last_port) => write!(f, "{}-{last_port}", self.addr),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+impl FromStr for TargetAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_unix_path(s) {
+            Some(path) => Ok(Self::Unix(path)),
+            None => Ok(Self::Tcp(s.parse()?)),
+        }
+    }
+}
+
+impl Display for SourceAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceAddr::Port(port) => write!(f, "{port}"),
+            SourceAddr::Range(first, last) => write!(f, "{first}-{last}"),
+            SourceAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Display for TargetAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetAddr::Tcp(addr) => write!(f, "{addr}"),
+            TargetAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SourceAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SourceAddrVisitor;
+
+        impl<'de> Visitor<'de> for SourceAddrVisitor {
+            type Value = SourceAddr;
+
+            fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
+                formatter.write_str("port number, port range or unix socket path")
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                u16::try_from(v)
+                    .map(SourceAddr::Port)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                u16::try_from(v)
+                    .map(SourceAddr::Port)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(SourceAddrVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for TargetSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for TargetAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -37,6 +300,368 @@ pub struct NamePlumbingConfig {
     pub source: u16,
     pub target: u16,
     pub resource: ResourceConfig,
+    #[serde(flatten)]
+    pub connection: ConnectionConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BalanceStrategy {
+    /// Targets are used in turn
+    #[default]
+    RoundRobin,
+    /// The target with the fewest open connections is used
+    LeastConnections,
+    /// The first target in the list that is not failing is used
+    FirstHealthy,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BalanceConfig {
+    #[serde(default)]
+    pub balance: BalanceStrategy,
+    /// Time a target is kept out of rotation after a failed connection attempt
+    #[serde(default = "default_failure_timeout_millis")]
+    pub failure_timeout_millis: u64,
+}
+
+fn default_failure_timeout_millis() -> u64 {
+    10_000
+}
+
+impl Default for BalanceConfig {
+    fn default() -> Self {
+        Self {
+            balance: Default::default(),
+            failure_timeout_millis: default_failure_timeout_millis(),
+        }
+    }
+}
+
+/// Shared TLS listener routing connections to the name-mode plumbing matching their SNI
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SniPlumbingConfig {
+    pub source: u16,
+    /// Port of the name-mode plumbing connections are routed to, defaults to `source`
+    #[serde(default)]
+    pub target: Option<u16>,
+    /// Name used for clients not sending SNI or sending an unknown name
+    #[serde(default)]
+    pub default_name: Option<String>,
+    /// Names set up when first requested, `.example.com` matches the domain and its subdomains;
+    /// other names must already be resolved
+    #[serde(default)]
+    pub on_demand_names: Vec<String>,
+    #[serde(flatten)]
+    pub connection: FrontendConnectionConfig,
+}
+
+/// Shared HTTP listener proxying requests to the name-mode plumbing matching their Host header
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HttpPlumbingConfig {
+    pub source: u16,
+    /// Port of the name-mode plumbing requests are proxied to, defaults to `source`
+    #[serde(default)]
+    pub target: Option<u16>,
+    /// Name used for requests to an unknown host
+    #[serde(default)]
+    pub default_name: Option<String>,
+    /// Names set up when first requested, `.example.com` matches the domain and its subdomains;
+    /// other names must already be resolved
+    #[serde(default)]
+    pub on_demand_names: Vec<String>,
+    /// Answers with a 503 page while the resource of the host is starting, instead of holding the request
+    #[serde(default)]
+    pub startup_page: Option<StartupPageConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct StartupPageConfig {
+    /// Seconds after which clients should retry, also used to refresh the HTML page
+    #[serde(default = "default_retry_after_secs")]
+    pub retry_after_secs: u64,
+    /// Handlebars HTML template replacing the default page, `name` and `retry_after` are available
+    #[serde(default)]
+    pub template: Option<PathBuf>,
+}
+
+fn default_retry_after_secs() -> u64 {
+    2
+}
+
+/// SOCKS5 proxy resolving the requested names with the name-mode plumbing
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SocksPlumbingConfig {
+    pub source: u16,
+    /// Handling of names without a matching name-mode plumbing and of requests by ip address
+    #[serde(default)]
+    pub unknown_names: UnknownNamePolicy,
+    /// Enables the UDP ASSOCIATE command
+    #[serde(default)]
+    pub udp: bool,
+    /// Names set up when first requested, `.example.com` matches the domain and its subdomains;
+    /// other names must already be resolved
+    #[serde(default)]
+    pub on_demand_names: Vec<String>,
+    #[serde(flatten)]
+    pub connection: FrontendConnectionConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownNamePolicy {
+    /// Requests are refused
+    #[default]
+    Reject,
+    /// Names are resolved with the system resolver and connected to directly
+    PassThrough,
+}
+
+/// HTTP forward proxy, `CONNECT` and absolute-form requests are routed to the name-mode plumbing
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HttpProxyPlumbingConfig {
+    pub source: u16,
+    /// Hosts reachable besides the name-mode plumbing, `*` allows any host and `.example.com` its subdomains
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Names set up when first requested, `.example.com` matches the domain and its subdomains;
+    /// other names must already be resolved
+    #[serde(default)]
+    pub on_demand_names: Vec<String>,
+    #[serde(flatten)]
+    pub connection: FrontendConnectionConfig,
+}
+
+/// Limits of the connections relayed by a front-end, the other connection settings belong to the name-mode plumbing
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct FrontendConnectionConfig {
+    /// Connections without traffic for this time are closed
+    #[serde(default)]
+    pub idle_timeout_millis: Option<u64>,
+    /// Connections are closed after this time regardless of their activity
+    #[serde(default)]
+    pub max_duration_millis: Option<u64>,
+}
+
+/// Per-socket settings of the forwarded connections
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConnectionConfig {
+    /// Connections without traffic for this time are closed
+    #[serde(default)]
+    pub idle_timeout_millis: Option<u64>,
+    /// Connections are closed after this time regardless of their activity
+    #[serde(default)]
+    pub max_duration_millis: Option<u64>,
+    /// Moves the data between plain TCP or unix sockets with `splice(2)`, disable to fall back to userspace copies
+    #[serde(default = "default_zero_copy")]
+    pub zero_copy: bool,
+    /// Attempts to connect again to the targets when all of them refused the connection
+    #[serde(default)]
+    pub connect_retries: u32,
+    /// Delay before the first connect retry, doubled at each further retry
+    #[serde(default = "default_connect_backoff_millis")]
+    pub connect_backoff_millis: u64,
+    /// Overall time limit to establish the connection to a target, retries included
+    #[serde(default)]
+    pub connect_deadline_millis: Option<u64>,
+    /// Expects a PROXY protocol header from the clients, e.g. when behind another proxy
+    #[serde(default)]
+    pub accept_proxy_protocol: bool,
+    /// Sends a PROXY protocol header with the original client address to the target
+    #[serde(default)]
+    pub send_proxy_protocol: Option<ProxyProtocolVersion>,
+    /// Terminates TLS on the listener
+    #[serde(default)]
+    pub tls: Option<TlsListenerConfig>,
+    /// Originates TLS towards the target
+    #[serde(default)]
+    pub target_tls: Option<TlsTargetConfig>,
+    /// Concurrent connections accepted by the socket, over all the ports of a port range
+    #[serde(default)]
+    pub max_connections: Option<NonZeroUsize>,
+    /// Connections waiting for a slot once a connection limit is reached, further connections are refused
+    #[serde(default)]
+    pub queue_length: usize,
+    /// Time a queued connection waits for a slot before being closed
+    #[serde(default = "default_queue_timeout_millis")]
+    pub queue_timeout_millis: u64,
+    /// Throughput and latency imposed on the connections, also changeable through the control API
+    #[serde(default)]
+    pub shaping: Option<ShapingConfig>,
+    /// Failures injected on the connections, also changeable through the control API
+    #[serde(default)]
+    pub faults: Option<FaultConfig>,
+    /// Records the exchanges of the connections, or replays them without the target
+    #[serde(default)]
+    pub session: Option<SessionConfig>,
+    /// Secondary target receiving a copy of the client data, its responses are discarded
+    #[serde(default)]
+    pub mirror: Option<MirrorConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MirrorConfig {
+    pub target: TargetAddr,
+    /// Client data waiting for a slow mirror target, the mirrored connection is abandoned past this limit
+    #[serde(default = "default_mirror_buffer_bytes")]
+    pub buffer_bytes: usize,
+}
+
+/// Session file of a socket, written in `Record` mode and served in `Replay` mode
+#[derive(Deserialize, Debug, Clone)]
+pub struct SessionConfig {
+    pub mode: SessionMode,
+    pub path: PathBuf,
+    /// Recorded bytes kept for a connection, larger sessions are discarded
+    #[serde(default = "default_max_session_bytes")]
+    pub max_session_bytes: usize,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionMode {
+    /// Connections are forwarded to the target and appended to the session file
+    Record,
+    /// Clients are answered with the recorded responses, neither the target nor the resource are used
+    Replay,
+}
+
+/// Failures injected on the new connections of a socket, for resilience testing
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct FaultConfig {
+    /// Share of the connections reset as soon as they are accepted, in percent
+    #[serde(default)]
+    pub refuse_percent: f64,
+    /// Connections are reset once this many bytes were forwarded, both directions included
+    #[serde(default)]
+    pub reset_after_bytes: Option<u64>,
+    /// Connections are reset after a random duration up to this value
+    #[serde(default)]
+    pub reset_after_millis: Option<u64>,
+    /// Data of the clients is read and discarded, the target is never contacted
+    #[serde(default)]
+    pub blackhole: bool,
+    /// Probability of each forwarded byte to be corrupted
+    #[serde(default)]
+    pub corrupt_rate: f64,
+}
+
+impl FaultConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(0.0..=100.0).contains(&self.refuse_percent) {
+            return Err(anyhow!("refuse_percent must be between 0 and 100, got {}", self.refuse_percent));
+        }
+        if !(0.0..=1.0).contains(&self.corrupt_rate) {
+            return Err(anyhow!("corrupt_rate must be between 0 and 1, got {}", self.corrupt_rate));
+        }
+        Ok(())
+    }
+}
+
+/// Network conditions simulated on the connections of a socket
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShapingConfig {
+    /// Throughput from the clients to the target shared by all the connections, in bytes per second
+    #[serde(default)]
+    pub upstream_bytes_per_sec: Option<u64>,
+    /// Throughput from the target to the clients shared by all the connections, in bytes per second
+    #[serde(default)]
+    pub downstream_bytes_per_sec: Option<u64>,
+    /// Throughput of each connection in each direction, in bytes per second
+    #[serde(default)]
+    pub connection_bytes_per_sec: Option<u64>,
+    /// Delay added to the data in each direction
+    #[serde(default)]
+    pub latency_millis: u64,
+    /// Random delay added on top of `latency_millis`, up to this value
+    #[serde(default)]
+    pub jitter_millis: u64,
+}
+
+/// Certificate presented to the clients, when not configured it is issued by the local CA for the plumbing name
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct TlsListenerConfig {
+    #[serde(default)]
+    pub cert: Option<PathBuf>,
+    #[serde(default)]
+    pub key: Option<PathBuf>,
+}
+
+impl TlsListenerConfig {
+    pub fn uses_local_ca(&self) -> bool {
+        self.cert.is_none() && self.key.is_none()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct TlsTargetConfig {
+    /// Name verified against the target certificate, defaults to the target ip address
+    #[serde(default)]
+    pub server_name: Option<String>,
+    /// CA certificates trusted to verify the target, defaults to the public web roots
+    #[serde(default)]
+    pub ca: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+fn default_mirror_buffer_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_max_session_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_zero_copy() -> bool {
+    true
+}
+
+fn default_connect_backoff_millis() -> u64 {
+    100
+}
+
+fn default_queue_timeout_millis() -> u64 {
+    10_000
+}
+
+impl ConnectionConfig {
+    /// Checks the values serde cannot reject on its own
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(faults) = &self.faults {
+            faults.validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_millis: None,
+            max_duration_millis: None,
+            zero_copy: default_zero_copy(),
+            connect_retries: 0,
+            connect_backoff_millis: default_connect_backoff_millis(),
+            connect_deadline_millis: None,
+            accept_proxy_protocol: false,
+            send_proxy_protocol: None,
+            tls: None,
+            target_tls: None,
+            max_connections: None,
+            queue_length: 0,
+            queue_timeout_millis: default_queue_timeout_millis(),
+            shaping: None,
+            faults: None,
+            session: None,
+            mirror: None,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -47,6 +672,41 @@ pub struct ResourceConfig {
     pub warmup_millis: u64,
     #[serde(default)]
     pub healthcheck_cmd: Option<HealthcheckCmdConfig>,
+    /// Maximum memory usage (cgroup `memory.max` syntax, e.g. `512M`)
+    #[serde(default)]
+    pub memory_max: Option<String>,
+    /// Maximum cpu usage expressed in number of cpus (e.g. `0.5`)
+    #[serde(default)]
+    pub cpu_max: Option<f64>,
+    /// Maximum number of processes
+    #[serde(default)]
+    pub pids_max: Option<u64>,
+    /// Concurrent connections forwarded to the resource, each socket having its own resource this is the
+    /// same limit as `max_connections` of the socket and the lower of the two applies
+    #[serde(default)]
+    pub max_connections: Option<NonZeroUsize>,
+}
+
+impl ResourceConfig {
+    /// Checks the values serde cannot reject on its own
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self.cpu_max {
+            // the kernel rejects a quota below 1ms per 100ms period
+            Some(cpu_max) if !cpu_max.is_finite() || cpu_max < 0.01 => Err(anyhow!("cpu_max must be at least 0.01 cpus, got {cpu_max}")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Rejects the settings that need the privileges given up with `run_as`
+    pub fn validate_unprivileged(&self) -> anyhow::Result<()> {
+        if self.setup.user.is_some() || self.setup.group.is_some() || !self.setup.supplementary_groups.is_empty() {
+            return Err(anyhow!("user, group and supplementary_groups of the setup command cannot be switched with run_as"));
+        }
+        if self.memory_max.is_some() || self.cpu_max.is_some() || self.pids_max.is_some() {
+            return Err(anyhow!("memory_max, cpu_max and pids_max cannot be applied with run_as, cgroups are created when the resource starts"));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -64,6 +724,21 @@ pub struct CommandConfig {
     pub args: Vec<String>,
     #[serde(default = "std::env::temp_dir")]
     pub workingdir: PathBuf,
+    /// User (name or uid) the command is executed as
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Group (name or gid) the command is executed as, defaults to the primary group of `user`
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Supplementary groups, defaults to the groups `user` is member of
+    #[serde(default)]
+    pub supplementary_groups: Vec<String>,
+    /// File mode creation mask of the command, e.g. 0o027
+    #[serde(default)]
+    pub umask: Option<u32>,
+    /// Prevents the command from gaining privileges through execve (e.g. setuid binaries)
+    #[serde(default)]
+    pub no_new_privileges: bool,
 }
 
 impl CommandConfig {
@@ -79,6 +754,11 @@ impl CommandConfig {
                 .transpose()?
                 .map(PathBuf::from)
                 .unwrap_or_else(std::env::temp_dir),
+            user: self.user.clone(),
+            group: self.group.clone(),
+            supplementary_groups: self.supplementary_groups.clone(),
+            umask: self.umask,
+            no_new_privileges: self.no_new_privileges,
         })
     }
 }
@@ -91,6 +771,93 @@ impl FromStr for CommandConfig {
             command: String::from(s),
             args: Vec::new(),
             workingdir: std::env::temp_dir(),
+            user: None,
+            group: None,
+            supplementary_groups: Vec::new(),
+            umask: None,
+            no_new_privileges: false,
         })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_unix_sources_and_targets() {
+        assert_eq!("unix:/run/app.sock".parse::<SourceAddr>().unwrap(), SourceAddr::Unix("/run/app.sock".into()));
+        assert_eq!("/run/app.sock".parse::<SourceAddr>().unwrap(), SourceAddr::Unix("/run/app.sock".into()));
+        assert_eq!("8080".parse::<SourceAddr>().unwrap(), SourceAddr::Port(8080));
+        assert!("app.sock".parse::<SourceAddr>().is_err());
+
+        assert_eq!("unix:/run/app.sock".parse::<TargetAddr>().unwrap(), TargetAddr::Unix("/run/app.sock".into()));
+        assert_eq!("127.0.0.1:80".parse::<TargetAddr>().unwrap(), TargetAddr::Tcp("127.0.0.1:80".parse().unwrap()));
+        assert!("localhost:80".parse::<TargetAddr>().is_err());
+    }
+
+    #[test]
+    fn deserializes_sockets() {
+        let config: AddrPlumbingConfig = toml::from_str("source = 8080\ntarget = \"unix:/run/app.sock\"").unwrap();
+        assert_eq!(config.source, SourceAddr::Port(8080));
+        assert_eq!(config.target.unwrap().addr, TargetAddr::Unix("/run/app.sock".into()));
+
+        let config: AddrPlumbingConfig = toml::from_str("source = \"/run/front.sock\"\ntargets = [\"127.0.0.1:80\"]").unwrap();
+        assert_eq!(config.source, SourceAddr::Unix("/run/front.sock".into()));
+        assert!(toml::from_str::<AddrPlumbingConfig>("source = 70000").is_err());
+    }
+
+    fn listener_targets(source: &str, targets: &[&str]) -> anyhow::Result<Vec<Vec<String>>> {
+        let config: AddrPlumbingConfig = toml::from_str(&format!("source = \"{source}\"\ntargets = {targets:?}")).unwrap();
+        Ok(config.listener_targets()?
+            .into_iter()
+            .map(|targets| targets.iter().map(ToString::to_string).collect())
+            .collect())
+    }
+
+    #[test]
+    fn parses_port_ranges() {
+        assert_eq!("30000-30002".parse::<SourceAddr>().unwrap(), SourceAddr::Range(30000, 30002));
+        assert_eq!("30000-30000".parse::<SourceAddr>().unwrap().ports(), Some(30000..=30000));
+        assert!("30002-30000".parse::<SourceAddr>().is_err());
+        assert!("30000-70000".parse::<SourceAddr>().is_err());
+
+        let spec = "127.0.0.1:40000-40002".parse::<TargetSpec>().unwrap();
+        assert_eq!(spec, TargetSpec { addr: "127.0.0.1:40000".parse().unwrap(), last_port: Some(40002) });
+        assert_eq!(spec.to_string(), "127.0.0.1:40000-40002");
+        assert_eq!("[::1]:40000-40002".parse::<TargetSpec>().unwrap().last_port, Some(40002));
+        assert_eq!("unix:/run/a-b.sock".parse::<TargetSpec>().unwrap().addr, TargetAddr::Unix("/run/a-b.sock".into()));
+    }
+
+    #[test]
+    fn shifts_targets_of_each_port() {
+        assert_eq!(
+            listener_targets("30000-30002", &["127.0.0.1:40000", "127.0.0.2:50000-50002"]).unwrap(),
+            [
+                ["127.0.0.1:40000", "127.0.0.2:50000"],
+                ["127.0.0.1:40001", "127.0.0.2:50001"],
+                ["127.0.0.1:40002", "127.0.0.2:50002"],
+            ],
+        );
+        assert_eq!(listener_targets("30000", &["unix:/run/app.sock"]).unwrap(), [["unix:/run/app.sock"]]);
+    }
+
+    #[test]
+    fn validates_cpu_max() {
+        let resource = |cpu_max: &str| toml::from_str::<ResourceConfig>(&format!("setup = \"true\"\ncpu_max = {cpu_max}")).unwrap();
+        assert!(resource("0.5").validate().is_ok());
+        assert!(resource("0.01").validate().is_ok());
+        assert!(resource("0.001").validate().is_err());
+        assert!(resource("-1.0").validate().is_err());
+        assert!(resource("nan").validate().is_err());
+        assert!(resource("inf").validate().is_err());
+    }
+
+    #[test]
+    fn rejects_mismatching_targets() {
+        assert!(listener_targets("30000-30002", &["127.0.0.1:40000-40001"]).is_err());
+        assert!(listener_targets("30000", &["127.0.0.1:40000-40001"]).is_err());
+        assert!(listener_targets("30000-30002", &["unix:/run/app.sock"]).is_err());
+        assert!(listener_targets("30000-30002", &["127.0.0.1:65535"]).is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem::MaybeUninit;
+use std::ptr;
+
+use anyhow::{bail, Context};
+
+use crate::config::CommandConfig;
+
+/// Largest buffer tried for a user or group entry, the lookup fails with ERANGE past it
+const MAX_ENTRY_BUFFER: usize = 1024 * 1024;
+
+/// Identity and process attributes applied to a child process before exec
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>,
+    groups: Option<Vec<libc::gid_t>>,
+    umask: Option<libc::mode_t>,
+    no_new_privileges: bool,
+}
+
+pub struct UserEntry {
+    pub name: String,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    /// `false` for a bare uid without passwd entry
+    pub in_passwd: bool,
+}
+
+impl Credentials {
+    /// Resolves the user and groups configured for the command, `None` is returned if nothing has to be changed
+    pub fn from_config(cfg: &CommandConfig) -> anyhow::Result<Option<Self>> {
+        if cfg.user.is_none() && cfg.group.is_none() && cfg.supplementary_groups.is_empty() && cfg.umask.is_none() && !cfg.no_new_privileges {
+            return Ok(None);
+        }
+        let user = cfg.user.as_deref().map(lookup_user).transpose()?;
+        let gid = match (&cfg.group, &user) {
+            (Some(group), _) => Some(lookup_group(group)?),
+            (None, Some(user)) => Some(user.gid),
+            (None, None) => None,
+        };
+        let groups = if !cfg.supplementary_groups.is_empty() {
+            Some(cfg.supplementary_groups.iter()
+                .map(|group| lookup_group(group))
+                .collect::<anyhow::Result<Vec<_>>>()?)
+        } else if let Some(ref user) = user {
+            Some(user_groups(user)?)
+        } else {
+            None
+        };
+
+        Ok(Some(Self {
+            uid: user.map(|u| u.uid),
+            gid,
+            groups,
+            umask: cfg.umask.map(|umask| umask as libc::mode_t),
+            no_new_privileges: cfg.no_new_privileges,
+        }))
+    }
+
+    /// Applies the credentials to the current process.
+    /// Must only perform async-signal-safe calls as it runs between fork and exec.
+    pub fn apply(&self) -> io::Result<()> {
+        unsafe {
+            if let Some(ref groups) = self.groups {
+                check(libc::setgroups(groups.len() as _, groups.as_ptr()))?;
+            }
+            if let Some(gid) = self.gid {
+                check(libc::setgid(gid))?;
+            }
+            if let Some(umask) = self.umask {
+                libc::umask(umask);
+            }
+            if let Some(uid) = self.uid {
+                check(libc::setuid(uid))?;
+            }
+            if self.no_new_privileges {
+                check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Looks up a user by name or numeric uid, a uid without passwd entry gets gid = uid
+pub fn lookup_user(user: &str) -> anyhow::Result<UserEntry> {
+    let uid = user.parse::<libc::uid_t>().ok();
+    let c_user = CString::new(user)?;
+    let mut buf = vec![0u8; entry_buffer_size(libc::_SC_GETPW_R_SIZE_MAX)];
+    let mut pwd = MaybeUninit::<libc::passwd>::uninit();
+    let mut result = ptr::null_mut();
+    let ret = retry_on_erange(&mut buf, |buf| match uid {
+        Some(uid) => unsafe { libc::getpwuid_r(uid, pwd.as_mut_ptr(), buf.as_mut_ptr().cast(), buf.len(), &mut result) },
+        None => unsafe { libc::getpwnam_r(c_user.as_ptr(), pwd.as_mut_ptr(), buf.as_mut_ptr().cast(), buf.len(), &mut result) },
+    });
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret)).with_context(|| format!("Error looking up user {user}"));
+    }
+    if result.is_null() {
+        return match uid {
+            Some(uid) => Ok(UserEntry { name: user.to_owned(), uid, gid: uid, in_passwd: false }),
+            None => bail!("User {user} not found"),
+        };
+    }
+    let pwd = unsafe { pwd.assume_init() };
+    Ok(UserEntry {
+        name: unsafe { CStr::from_ptr(pwd.pw_name) }.to_string_lossy().into_owned(),
+        uid: pwd.pw_uid,
+        gid: pwd.pw_gid,
+        in_passwd: true,
+    })
+}
+
+/// Looks up a group by name or numeric gid
+pub fn lookup_group(group: &str) -> anyhow::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+    let c_group = CString::new(group)?;
+    let mut buf = vec![0u8; entry_buffer_size(libc::_SC_GETGR_R_SIZE_MAX)];
+    let mut grp = MaybeUninit::<libc::group>::uninit();
+    let mut result = ptr::null_mut();
+    let ret = retry_on_erange(&mut buf, |buf| unsafe {
+        libc::getgrnam_r(c_group.as_ptr(), grp.as_mut_ptr(), buf.as_mut_ptr().cast(), buf.len(), &mut result)
+    });
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret)).with_context(|| format!("Error looking up group {group}"));
+    }
+    if result.is_null() {
+        bail!("Group {group} not found");
+    }
+    Ok(unsafe { grp.assume_init() }.gr_gid)
+}
+
+/// Initial buffer size suggested by the system for a `getpwnam_r`/`getgrnam_r` call
+fn entry_buffer_size(name: libc::c_int) -> usize {
+    match unsafe { libc::sysconf(name) } {
+        size if size > 0 => (size as usize).min(MAX_ENTRY_BUFFER),
+        _ => 1024,
+    }
+}
+
+/// Calls `lookup` with a larger buffer as long as the entry does not fit in it
+fn retry_on_erange(buf: &mut Vec<u8>, mut lookup: impl FnMut(&mut [u8]) -> libc::c_int) -> libc::c_int {
+    loop {
+        let ret = lookup(buf);
+        if ret != libc::ERANGE || buf.len() >= MAX_ENTRY_BUFFER {
+            return ret;
+        }
+        buf.resize(buf.len() * 2, 0);
+    }
+}
+
+/// Groups the user is member of, as `initgroups` would set them; none for a user without passwd entry
+pub fn user_groups(user: &UserEntry) -> anyhow::Result<Vec<libc::gid_t>> {
+    if !user.in_passwd {
+        return Ok(Vec::new());
+    }
+    let c_user = CString::new(user.name.as_str())?;
+    let mut count: libc::c_int = 32;
+    loop {
+        let mut groups = vec![0 as libc::gid_t; count as usize];
+        let ret = unsafe { libc::getgrouplist(c_user.as_ptr(), user.gid, groups.as_mut_ptr(), &mut count) };
+        if ret >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+        if count as usize <= groups.len() {
+            count *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_buffer_until_entry_fits() {
+        let mut buf = vec![0u8; 1];
+        let mut calls = 0;
+        let ret = retry_on_erange(&mut buf, |buf| {
+            calls += 1;
+            if buf.len() < 64 { libc::ERANGE } else { 0 }
+        });
+        assert_eq!((ret, calls, buf.len()), (0, 7, 64));
+    }
+
+    #[test]
+    fn gives_up_past_max_buffer() {
+        let mut buf = vec![0u8; MAX_ENTRY_BUFFER / 2];
+        assert_eq!(retry_on_erange(&mut buf, |_| libc::ERANGE), libc::ERANGE);
+        assert_eq!(buf.len(), MAX_ENTRY_BUFFER);
+    }
+
+    #[test]
+    fn looks_up_root() {
+        let by_name = lookup_user("root").unwrap();
+        let by_uid = lookup_user("0").unwrap();
+        assert_eq!((by_name.uid, by_uid.name.as_str()), (0, "root"));
+        assert_eq!(lookup_group("root").unwrap(), 0);
+        assert!(lookup_user("no-such-user-plumber").is_err());
+    }
+
+    #[test]
+    fn accepts_uid_without_passwd_entry() {
+        let user = lookup_user("4000000000").unwrap();
+        assert_eq!((user.uid, user.gid, user.in_passwd), (4000000000, 4000000000, false));
+        assert!(user_groups(&user).unwrap().is_empty());
+    }
+}
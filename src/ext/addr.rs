@@ -10,7 +10,7 @@ impl Increment for IpAddr {
             IpAddr::V4(ipv4) => {
                 let value = u32::from(*ipv4) + 1;
                 *self = Self::from(Ipv4Addr::from(value));
-                self.clone()
+                *self
             },
             IpAddr::V6(_) => unimplemented!(),
         }
@@ -0,0 +1,168 @@
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::os::fd::RawFd;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::config::FaultConfig;
+
+/// Failures injected on the connections of a socket, changeable while the socket is running
+pub struct FaultInjector {
+    config: Mutex<Option<FaultConfig>>,
+}
+
+/// Error of the connections reset on purpose
+#[derive(Debug)]
+struct InjectedFault;
+
+/// Stream resetting the client connection and corrupting the data read from it according to the faults
+pub struct FaultyStream<S> {
+    inner: S,
+    /// Client socket, closed with a reset when a fault is triggered
+    client_fd: Option<RawFd>,
+    /// Bytes read by both streams of the connection
+    forwarded: Arc<AtomicU64>,
+    reset_after_bytes: Option<u64>,
+    deadline: Option<Pin<Box<Sleep>>>,
+    corrupt_rate: f64,
+}
+
+impl FaultInjector {
+    pub fn new(config: Option<FaultConfig>) -> Self {
+        Self { config: Mutex::new(config) }
+    }
+
+    pub fn config(&self) -> Option<FaultConfig> {
+        *self.config.lock().expect("Broken faults mutex")
+    }
+
+    /// Applies to the connections accepted from now on
+    pub fn set_config(&self, config: Option<FaultConfig>) {
+        *self.config.lock().expect("Broken faults mutex") = config;
+    }
+}
+
+impl FaultConfig {
+    /// Draws whether a new connection is refused
+    pub fn refuses(&self) -> bool {
+        self.refuse_percent > 0.0 && fastrand::f64() * 100.0 < self.refuse_percent
+    }
+
+    /// Whether the streams of the connections must be wrapped in a [`FaultyStream`]
+    pub fn disrupts(&self) -> bool {
+        self.reset_after_bytes.is_some() || self.reset_after_millis.is_some() || self.corrupt_rate > 0.0
+    }
+
+    /// Wraps both streams of a connection, they share the count of forwarded bytes and the reset deadline
+    pub fn wrap<C, T>(&self, client: C, target: T, client_fd: Option<RawFd>) -> (FaultyStream<C>, FaultyStream<T>) {
+        let forwarded = Arc::new(AtomicU64::new(0));
+        let deadline = self.reset_after_millis
+            .map(|millis| Instant::now() + Duration::from_millis(fastrand::u64(0..=millis)));
+        (
+            self.stream(client, client_fd, forwarded.clone(), deadline),
+            self.stream(target, client_fd, forwarded, deadline),
+        )
+    }
+
+    fn stream<S>(&self, inner: S, client_fd: Option<RawFd>, forwarded: Arc<AtomicU64>, deadline: Option<Instant>) -> FaultyStream<S> {
+        FaultyStream {
+            inner,
+            client_fd,
+            forwarded,
+            reset_after_bytes: self.reset_after_bytes,
+            deadline: deadline.map(|deadline| Box::pin(tokio::time::sleep_until(deadline))),
+            corrupt_rate: self.corrupt_rate,
+        }
+    }
+}
+
+/// Closes the socket with a TCP reset instead of a graceful shutdown once it is dropped
+pub fn abort_on_close(fd: RawFd) {
+    let linger = libc::linger { l_onoff: 1, l_linger: 0 };
+    let ret = unsafe {
+        libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_LINGER, (&linger as *const libc::linger).cast(), std::mem::size_of::<libc::linger>() as libc::socklen_t)
+    };
+    if ret != 0 {
+        log::warn!("Error setting SO_LINGER - {}", io::Error::last_os_error());
+    }
+}
+
+/// Whether `err` was caused by an injected fault
+pub fn is_injected(err: &io::Error) -> bool {
+    err.get_ref().map(|err| err.is::<InjectedFault>()).unwrap_or(false)
+}
+
+impl fmt::Display for InjectedFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("injected connection reset")
+    }
+}
+
+impl Error for InjectedFault {}
+
+impl<S> FaultyStream<S> {
+    fn reset(&self) -> io::Error {
+        if let Some(fd) = self.client_fd {
+            abort_on_close(fd);
+        }
+        io::Error::new(io::ErrorKind::ConnectionReset, InjectedFault)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for FaultyStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if let Some(deadline) = &mut this.deadline {
+            if deadline.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(this.reset()));
+            }
+        }
+        let forwarded = this.forwarded.load(Ordering::Relaxed);
+        if this.reset_after_bytes.map(|limit| forwarded >= limit).unwrap_or(false) {
+            return Poll::Ready(Err(this.reset()));
+        }
+
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        // the data past the limit is dropped, the connection is reset at the next read
+        if let Some(limit) = this.reset_after_bytes {
+            let allowed = limit.saturating_sub(this.forwarded.load(Ordering::Relaxed)) as usize;
+            if allowed == 0 {
+                return Poll::Ready(Err(this.reset()));
+            }
+            if buf.filled().len() - before > allowed {
+                buf.set_filled(before + allowed);
+            }
+        }
+        let read = &mut buf.filled_mut()[before..];
+        if this.corrupt_rate > 0.0 {
+            for byte in read.iter_mut().filter(|_| fastrand::f64() < this.corrupt_rate) {
+                *byte ^= 1 << fastrand::u8(0..8);
+            }
+        }
+        this.forwarded.fetch_add(read.len() as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FaultyStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,277 @@
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+use crate::config::{ConnectionConfig, FrontendConnectionConfig};
+use crate::net::Stream;
+use crate::shaping::Direction;
+
+#[cfg(target_os = "linux")]
+mod splice;
+
+const BUFFER_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct ForwardOptions {
+    /// Maximum time without traffic in any direction
+    pub idle_timeout: Option<Duration>,
+    /// Maximum lifetime of the connection
+    pub max_duration: Option<Duration>,
+    /// Moves data between sockets without copying it to userspace when supported
+    pub zero_copy: bool,
+    /// Updated while the data is forwarded, reports the bytes of the connections that failed as well
+    pub progress: Option<Arc<Progress>>,
+}
+
+impl From<&FrontendConnectionConfig> for ForwardOptions {
+    fn from(value: &FrontendConnectionConfig) -> Self {
+        Self {
+            idle_timeout: value.idle_timeout_millis.map(Duration::from_millis),
+            max_duration: value.max_duration_millis.map(Duration::from_millis),
+            zero_copy: true,
+            progress: None,
+        }
+    }
+}
+
+impl From<&ConnectionConfig> for ForwardOptions {
+    fn from(value: &ConnectionConfig) -> Self {
+        Self {
+            idle_timeout: value.idle_timeout_millis.map(Duration::from_millis),
+            max_duration: value.max_duration_millis.map(Duration::from_millis),
+            zero_copy: value.zero_copy,
+            progress: None,
+        }
+    }
+}
+
+/// Bytes transferred in each direction
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Transferred {
+    /// From client to target
+    pub upstream: u64,
+    /// From target to client
+    pub downstream: u64,
+}
+
+/// Bytes forwarded so far in each direction
+#[derive(Debug, Default)]
+pub struct Progress {
+    upstream: AtomicU64,
+    downstream: AtomicU64,
+}
+
+/// Last time some traffic went through the connection, in millis since `start`
+struct Activity {
+    start: Instant,
+    last: AtomicU64,
+    progress: Arc<Progress>,
+}
+
+impl Progress {
+    /// Counts bytes exchanged with the client outside of `forward`
+    pub fn add(&self, direction: Direction, bytes: usize) {
+        let counter = match direction {
+            Direction::Upstream => &self.upstream,
+            Direction::Downstream => &self.downstream,
+        };
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn transferred(&self) -> Transferred {
+        Transferred {
+            upstream: self.upstream.load(Ordering::Relaxed),
+            downstream: self.downstream.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Activity {
+    fn new(options: &ForwardOptions) -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            progress: options.progress.clone().unwrap_or_default(),
+        }
+    }
+
+    fn touch(&self) {
+        self.last.store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+}
+
+/// Forwards data between two sockets, using `splice(2)` on linux when zero copy is enabled
+pub async fn forward_sockets(client: Stream, target: Stream, options: &ForwardOptions) -> io::Result<Transferred> {
+    #[cfg(target_os = "linux")]
+    if options.zero_copy {
+        match splice::PipePair::new() {
+            Ok(pipes) => {
+                let activity = Activity::new(options);
+                return with_limits(options, &activity, splice::forward(&client, &target, pipes, &activity)).await;
+            }
+            Err(err) => log::debug!("Could not create pipes, falling back to userspace copy - {err}"),
+        }
+    }
+    forward(client, target, options).await
+}
+
+/// Copies data in both directions until both sides are done.
+/// When one side reaches EOF the write half of the other side is shut down, so that half-closed connections
+/// keep receiving data in the opposite direction.
+pub async fn forward<A, B>(client: A, target: B, options: &ForwardOptions) -> io::Result<Transferred>
+    where
+        A: AsyncRead + AsyncWrite + Unpin,
+        B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_reader, mut client_writer) = tokio::io::split(client);
+    let (mut target_reader, mut target_writer) = tokio::io::split(target);
+    let activity = Activity::new(options);
+
+    let copy = async {
+        let (upstream, downstream) = tokio::try_join!(
+            copy_half(&mut client_reader, &mut target_writer, &activity, &activity.progress.upstream),
+            copy_half(&mut target_reader, &mut client_writer, &activity, &activity.progress.downstream),
+        )?;
+        Ok(Transferred { upstream, downstream })
+    };
+    with_limits(options, &activity, copy).await
+}
+
+/// Reads and drops the client data until the client closes the connection or a timeout is reached
+pub async fn discard<R: AsyncRead + Unpin>(mut client: R, options: &ForwardOptions) -> io::Result<Transferred> {
+    let activity = Activity::new(options);
+    let copy = async {
+        let mut sink = tokio::io::sink();
+        let upstream = copy_half(&mut client, &mut sink, &activity, &activity.progress.upstream).await?;
+        Ok(Transferred { upstream, downstream: 0 })
+    };
+    with_limits(options, &activity, copy).await
+}
+
+/// Applies idle and maximum duration timeouts to a copy future
+async fn with_limits<F>(options: &ForwardOptions, activity: &Activity, copy: F) -> io::Result<Transferred>
+    where
+        F: Future<Output=io::Result<Transferred>>,
+{
+    let bounded = async {
+        tokio::select! {
+            res = copy => res,
+            err = idle_watchdog(activity, options.idle_timeout) => Err(err),
+        }
+    };
+    match options.max_duration {
+        Some(max_duration) => tokio::time::timeout(max_duration, bounded).await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "maximum connection duration reached"))),
+        None => bounded.await,
+    }
+}
+
+async fn copy_half<R, W>(reader: &mut R, writer: &mut W, activity: &Activity, progress: &AtomicU64) -> io::Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    let mut total = 0;
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        activity.touch();
+        writer.write_all(&buf[..read]).await?;
+        total += read as u64;
+        progress.fetch_add(read as u64, Ordering::Relaxed);
+    }
+    match writer.shutdown().await {
+        Err(err) if err.kind() != io::ErrorKind::NotConnected => Err(err),
+        _ => Ok(total),
+    }
+}
+
+async fn idle_watchdog(activity: &Activity, idle_timeout: Option<Duration>) -> io::Error {
+    let Some(idle_timeout) = idle_timeout else {
+        return futures::future::pending().await;
+    };
+    loop {
+        let deadline = activity.last() + idle_timeout;
+        if deadline <= Instant::now() {
+            return io::Error::new(io::ErrorKind::TimedOut, "connection idle timeout reached");
+        }
+        tokio::time::sleep_until(deadline).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_forwarding_after_half_close() {
+        let (mut client, client_side) = duplex(1024);
+        let (target_side, mut target) = duplex(1024);
+        let forwarding = tokio::spawn(async move { forward(client_side, target_side, &ForwardOptions::default()).await });
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        target.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+
+        // the client stopped writing, the response still reaches it
+        target.write_all(b"response").await.unwrap();
+        target.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+
+        let transferred = forwarding.await.unwrap().unwrap();
+        assert_eq!((transferred.upstream, transferred.downstream), (7, 8));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_idle_connections() {
+        let (_client, client_side) = duplex(1024);
+        let (target_side, _target) = duplex(1024);
+        let options = ForwardOptions { idle_timeout: Some(Duration::from_secs(5)), ..ForwardOptions::default() };
+        let start = Instant::now();
+        let err = forward(client_side, target_side, &options).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_connections_past_max_duration() {
+        let (mut client, client_side) = duplex(1024);
+        let (target_side, mut target) = duplex(1024);
+        let options = ForwardOptions {
+            idle_timeout: Some(Duration::from_secs(1)),
+            max_duration: Some(Duration::from_secs(10)),
+            ..ForwardOptions::default()
+        };
+        // the client keeps sending well within the idle timeout
+        tokio::spawn(async move {
+            while client.write_all(b"ping").await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        });
+        tokio::spawn(async move { tokio::io::copy(&mut target, &mut tokio::io::sink()).await });
+
+        let start = Instant::now();
+        let err = forward(client_side, target_side, &options).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "maximum connection duration reached");
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,101 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::io::Interest;
+
+use crate::net::Stream;
+
+use super::{Activity, Transferred};
+
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// A pipe for each direction, data is moved socket -> pipe -> socket without crossing userspace
+pub struct PipePair {
+    upstream: Pipe,
+    downstream: Pipe,
+}
+
+struct Pipe {
+    reader: OwnedFd,
+    writer: OwnedFd,
+}
+
+impl PipePair {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            upstream: Pipe::new()?,
+            downstream: Pipe::new()?,
+        })
+    }
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0 as RawFd; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            reader: unsafe { OwnedFd::from_raw_fd(fds[0]) },
+            writer: unsafe { OwnedFd::from_raw_fd(fds[1]) },
+        })
+    }
+}
+
+pub async fn forward(client: &Stream, target: &Stream, pipes: PipePair, activity: &Activity) -> io::Result<Transferred> {
+    let (upstream, downstream) = tokio::try_join!(
+        splice_half(client, target, &pipes.upstream, activity, &activity.progress.upstream),
+        splice_half(target, client, &pipes.downstream, activity, &activity.progress.downstream),
+    )?;
+    Ok(Transferred { upstream, downstream })
+}
+
+async fn splice_half(source: &Stream, destination: &Stream, pipe: &Pipe, activity: &Activity, progress: &AtomicU64) -> io::Result<u64> {
+    let mut total = 0;
+    loop {
+        let mut pending = when_ready(source, Interest::READABLE, || {
+            splice(source.as_raw_fd(), pipe.writer.as_raw_fd(), PIPE_CAPACITY)
+        }).await?;
+        if pending == 0 {
+            break;
+        }
+        activity.touch();
+        total += pending as u64;
+        progress.fetch_add(pending as u64, Ordering::Relaxed);
+        while pending > 0 {
+            pending -= when_ready(destination, Interest::WRITABLE, || {
+                splice(pipe.reader.as_raw_fd(), destination.as_raw_fd(), pending)
+            }).await?;
+        }
+    }
+    if unsafe { libc::shutdown(destination.as_raw_fd(), libc::SHUT_WR) } < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::NotConnected {
+            return Err(err);
+        }
+    }
+    Ok(total)
+}
+
+/// Runs the io operation once the socket is ready, retrying when readiness was spurious
+async fn when_ready<R>(stream: &Stream, interest: Interest, mut op: impl FnMut() -> io::Result<R>) -> io::Result<R> {
+    loop {
+        stream.ready(interest).await?;
+        match stream.try_io(interest, &mut op) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            res => return res,
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let moved = unsafe {
+        libc::splice(from, std::ptr::null_mut(), to, std::ptr::null_mut(), len, libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK)
+    };
+    if moved < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(moved as usize)
+    }
+}
@@ -0,0 +1,295 @@
+use std::convert::Infallible;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use handlebars::Handlebars;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CACHE_CONTROL, CONNECTION, CONTENT_TYPE, HOST, RETRY_AFTER, UPGRADE};
+use hyper::http::uri::Authority;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Client, Request, Response, StatusCode, Uri, Version};
+
+use crate::cmd_resource::{ResourceStatus, SharedResource};
+use crate::config::HttpPlumbingConfig;
+use crate::forward::{forward, ForwardOptions};
+use crate::net::PeerAddr;
+use crate::plumber::Plumber;
+use crate::resolver::NameResolver;
+
+/// Headers meaningful only for a single connection, never forwarded
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Time a request waits for a stopped or starting resource before the startup page is returned
+const STARTUP_GRACE: Duration = Duration::from_secs(1);
+
+const DEFAULT_STARTUP_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="{{retry_after}}">
+<title>Starting {{name}}</title>
+</head>
+<body>
+<h1>{{name}} is starting</h1>
+<p>This page will refresh in {{retry_after}} seconds.</p>
+</body>
+</html>
+"#;
+
+struct HttpRouter {
+    plumber: Plumber,
+    name_resolver: NameResolver,
+    client: Client<HttpConnector>,
+    target_port: u16,
+    default_name: Option<String>,
+    on_demand_names: Vec<String>,
+    startup_page: Option<StartupPage>,
+}
+
+struct StartupPage {
+    retry_after: u64,
+    template: String,
+}
+
+/// Starts a reverse proxy routing requests by their Host header to the name-mode plumbing
+pub fn attach(plumber: &Plumber, name_resolver: NameResolver, name: &str, socket_name: &str, in_addr: IpAddr, config: HttpPlumbingConfig) -> anyhow::Result<()> {
+    let source = SocketAddr::new(in_addr, config.source);
+    log::info!("Starting HTTP router on {source}");
+    let listener = plumber.bind_frontend(&format!("{name}/{socket_name}"), source)?;
+    let startup_page = config.startup_page
+        .map(|page| -> anyhow::Result<_> {
+            let template = match page.template {
+                Some(path) => fs::read_to_string(&path)
+                    .with_context(|| format!("Error reading startup page template {path:?}"))?,
+                None => DEFAULT_STARTUP_TEMPLATE.to_string(),
+            };
+            Ok(StartupPage { retry_after: page.retry_after_secs, template })
+        })
+        .transpose()?;
+    let router = Arc::new(HttpRouter {
+        plumber: plumber.clone(),
+        name_resolver,
+        client: Client::new(),
+        target_port: config.target.unwrap_or(config.source),
+        default_name: config.default_name,
+        on_demand_names: config.on_demand_names,
+        startup_page,
+    });
+    let shutdown = plumber.shutdown_signal();
+    super::spawn(plumber, listener, move |stream, peer| {
+        let router = router.clone();
+        let mut shutdown = shutdown.clone();
+        async move {
+            let service_peer = peer.clone();
+            let service = service_fn(move |req| {
+                let router = router.clone();
+                let peer = service_peer.clone();
+                async move { Ok::<_, Infallible>(router.proxy(req, &peer).await) }
+            });
+            let connection = Http::new()
+                .http1_only(true)
+                .serve_connection(stream, service)
+                .with_upgrades();
+            tokio::pin!(connection);
+            tokio::select! {
+                res = &mut connection => res,
+                _ = shutdown.changed() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            }.with_context(|| format!("Error serving HTTP connection from {peer}"))
+        }
+    });
+    Ok(())
+}
+
+impl HttpRouter {
+    async fn proxy(&self, req: Request<Body>, peer: &PeerAddr) -> Response<Body> {
+        let Some(host) = request_host(&req) else {
+            return error_response(StatusCode::BAD_REQUEST, "Missing Host header");
+        };
+        let resolved = super::route_name(&self.name_resolver, &host, &self.on_demand_names).map(|ip| (host.as_str(), ip))
+            .or_else(|| self.default_name.as_deref().and_then(|name| Some((name, self.name_resolver.resolve(name)?))));
+        let Some((name, ip)) = resolved else {
+            return error_response(StatusCode::NOT_FOUND, "No plumbing found for host");
+        };
+        if let Some(page) = &self.startup_page {
+            let resource = self.plumber.socket_resource(name, self.target_port);
+            if let Some(resource) = resource {
+                if !wait_running(&resource).await {
+                    return page.response(name, accepts_json(&req));
+                }
+            }
+        }
+        let target = SocketAddr::new(ip, self.target_port);
+        match self.forward(req, peer, &host, target).await {
+            Ok(response) => response,
+            Err(err) => {
+                log::error!("Error proxying request for {host} to {target} - {err:#}");
+                error_response(StatusCode::BAD_GATEWAY, "Error contacting the target")
+            }
+        }
+    }
+
+    async fn forward(&self, mut req: Request<Body>, peer: &PeerAddr, host: &str, target: SocketAddr) -> anyhow::Result<Response<Body>> {
+        let upgrade = is_upgrade(req.headers());
+        let client_upgrade = upgrade.then(|| hyper::upgrade::on(&mut req));
+
+        let (mut parts, body) = req.into_parts();
+        let path = parts.uri.path_and_query().map(|path| path.as_str()).unwrap_or("/").to_string();
+        parts.uri = Uri::from_str(&format!("http://{target}{path}"))?;
+        parts.version = Version::HTTP_11;
+        remove_hop_by_hop(&mut parts.headers, upgrade);
+        add_forwarded_headers(&mut parts.headers, peer, host)?;
+
+        log::debug!("Proxying {} {host}{path} to {target}", parts.method);
+        let mut response = self.client.request(Request::from_parts(parts, body)).await
+            .context("Error sending request")?;
+
+        match client_upgrade {
+            Some(client_upgrade) if response.status() == StatusCode::SWITCHING_PROTOCOLS => {
+                let target_upgrade = hyper::upgrade::on(&mut response);
+                tokio::spawn(async move {
+                    let upgraded = tokio::try_join!(client_upgrade, target_upgrade);
+                    let out = match upgraded {
+                        Ok((client, target)) => forward(client, target, &ForwardOptions::default()).await
+                            .map_err(anyhow::Error::from),
+                        Err(err) => Err(err.into()),
+                    };
+                    if let Err(err) = out {
+                        log::error!("Error forwarding upgraded connection - {err:#}");
+                    }
+                });
+            }
+            _ => remove_hop_by_hop(response.headers_mut(), false),
+        }
+        Ok(response)
+    }
+}
+
+impl StartupPage {
+    fn response(&self, name: &str, json: bool) -> Response<Body> {
+        let params = serde_json::json!({ "status": "starting", "name": name, "retry_after": self.retry_after });
+        let (content_type, body) = if json {
+            ("application/json", params.to_string())
+        } else {
+            match Handlebars::new().render_template(&self.template, &params) {
+                Ok(body) => ("text/html; charset=utf-8", body),
+                Err(err) => {
+                    log::error!("Error rendering startup page - {err}");
+                    ("text/plain", format!("{name} is starting"))
+                }
+            }
+        };
+        Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(RETRY_AFTER, self.retry_after)
+            .header(CACHE_CONTROL, "no-store")
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .expect("Valid startup response")
+    }
+}
+
+/// Starts the resource when stopped and waits a short time for it to be running
+async fn wait_running(resource: &SharedResource) -> bool {
+    let mut status = resource.subscribe();
+    if resource.status() == ResourceStatus::Stopped {
+        let resource = resource.clone();
+        tokio::spawn(async move {
+            if let Err(err) = resource.ensure_running().await {
+                log::error!("Error starting resource - {err:#}");
+            }
+        });
+    }
+    let running = async {
+        while *status.borrow_and_update() != ResourceStatus::Running {
+            if status.changed().await.is_err() {
+                return false;
+            }
+        }
+        true
+    };
+    tokio::time::timeout(STARTUP_GRACE, running).await.unwrap_or(false)
+}
+
+fn accepts_json(req: &Request<Body>) -> bool {
+    req.headers().get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains("application/json") && !accept.contains("text/html"))
+        .unwrap_or(false)
+}
+
+/// Host the request is addressed to, without port
+fn request_host(req: &Request<Body>) -> Option<String> {
+    let authority = req.headers().get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| Authority::from_str(host).ok())
+        .or_else(|| req.uri().authority().cloned())?;
+    Some(authority.host().trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase())
+}
+
+fn is_upgrade(headers: &HeaderMap) -> bool {
+    headers.contains_key(UPGRADE) && connection_tokens(headers).any(|token| token.eq_ignore_ascii_case("upgrade"))
+}
+
+fn connection_tokens(headers: &HeaderMap) -> impl Iterator<Item=String> + '_ {
+    headers.get_all(CONNECTION).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+}
+
+/// Upgrade requests keep the `Connection` and `Upgrade` headers needed by the target to switch protocol
+pub(super) fn remove_hop_by_hop(headers: &mut HeaderMap, keep_upgrade: bool) {
+    let listed = connection_tokens(headers).collect::<Vec<_>>();
+    for name in listed.iter().map(String::as_str).chain(HOP_BY_HOP_HEADERS) {
+        if keep_upgrade && (name == "connection" || name == "upgrade") {
+            continue;
+        }
+        headers.remove(name);
+    }
+    if keep_upgrade {
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    }
+}
+
+fn add_forwarded_headers(headers: &mut HeaderMap, peer: &PeerAddr, host: &str) -> anyhow::Result<()> {
+    let forwarded_for = HeaderName::from_static("x-forwarded-for");
+    if let Some(addr) = peer.socket_addr() {
+        let value = match headers.get(&forwarded_for).and_then(|value| value.to_str().ok()) {
+            Some(previous) => format!("{previous}, {}", addr.ip()),
+            None => addr.ip().to_string(),
+        };
+        headers.insert(forwarded_for, HeaderValue::from_str(&value)?);
+    }
+    let forwarded_host = match headers.get(HOST) {
+        Some(value) => value.clone(),
+        None => HeaderValue::from_str(host)?,
+    };
+    headers.insert(HeaderName::from_static("x-forwarded-host"), forwarded_host);
+    headers.insert(HeaderName::from_static("x-forwarded-proto"), HeaderValue::from_static("http"));
+    Ok(())
+}
+
+pub(super) fn error_response(status: StatusCode, message: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message))
+        .expect("Valid error response")
+}
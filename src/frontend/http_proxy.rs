@@ -0,0 +1,167 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Context;
+use hyper::client::HttpConnector;
+use hyper::http::uri::{Authority, Scheme};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Client, Method, Request, Response, StatusCode, Uri, Version};
+use tokio::net::TcpStream;
+
+use crate::config::HttpProxyPlumbingConfig;
+use crate::forward::{forward, ForwardOptions};
+use crate::plumber::Plumber;
+use crate::resolver::NameResolver;
+
+use super::http::{error_response, remove_hop_by_hop};
+
+const DEFAULT_HTTP_PORT: u16 = 80;
+
+struct HttpProxy {
+    name_resolver: NameResolver,
+    client: Client<HttpConnector>,
+    allow: Vec<String>,
+    on_demand_names: Vec<String>,
+    options: ForwardOptions,
+}
+
+/// Starts an HTTP forward proxy, usable through `HTTP_PROXY`/`HTTPS_PROXY`
+pub fn attach(plumber: &Plumber, name_resolver: NameResolver, name: &str, socket_name: &str, in_addr: IpAddr, config: HttpProxyPlumbingConfig) -> anyhow::Result<()> {
+    let source = SocketAddr::new(in_addr, config.source);
+    log::info!("Starting HTTP proxy on {source}");
+    let listener = plumber.bind_frontend(&format!("{name}/{socket_name}"), source)?;
+    let proxy = Arc::new(HttpProxy {
+        name_resolver,
+        client: Client::new(),
+        allow: config.allow.iter().map(|host| host.to_ascii_lowercase()).collect(),
+        on_demand_names: config.on_demand_names,
+        options: ForwardOptions::from(&config.connection),
+    });
+    let shutdown = plumber.shutdown_signal();
+    super::spawn(plumber, listener, move |stream, peer| {
+        let proxy = proxy.clone();
+        let mut shutdown = shutdown.clone();
+        async move {
+            let service = service_fn(move |req| {
+                let proxy = proxy.clone();
+                async move { Ok::<_, Infallible>(proxy.handle(req).await) }
+            });
+            let connection = Http::new()
+                .http1_only(true)
+                .serve_connection(stream, service)
+                .with_upgrades();
+            tokio::pin!(connection);
+            tokio::select! {
+                res = &mut connection => res,
+                _ = shutdown.changed() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            }.with_context(|| format!("Error serving HTTP proxy connection from {peer}"))
+        }
+    });
+    Ok(())
+}
+
+impl HttpProxy {
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        if req.method() == Method::CONNECT {
+            self.connect(req).await
+        } else {
+            self.forward(req).await
+        }
+    }
+
+    /// Connects to the requested authority and tunnels the connection once the client is answered
+    async fn connect(&self, mut req: Request<Body>) -> Response<Body> {
+        let Some(authority) = req.uri().authority().cloned() else {
+            return error_response(StatusCode::BAD_REQUEST, "CONNECT requires host:port");
+        };
+        let Some(port) = authority.port_u16() else {
+            return error_response(StatusCode::BAD_REQUEST, "CONNECT requires host:port");
+        };
+        let target = match self.resolve(&authority, port).await {
+            Ok(target) => target,
+            Err(response) => return response,
+        };
+        let outgoing = match TcpStream::connect(target).await {
+            Ok(outgoing) => outgoing,
+            Err(err) => {
+                log::error!("Error connecting to {authority} at {target} - {err}");
+                return error_response(StatusCode::BAD_GATEWAY, "Error contacting the target");
+            }
+        };
+        log::debug!("Tunnelling {authority} to {target}");
+        let client_upgrade = hyper::upgrade::on(&mut req);
+        let options = self.options.clone();
+        tokio::spawn(async move {
+            let out = match client_upgrade.await {
+                Ok(client) => forward(client, outgoing, &options).await.map_err(anyhow::Error::from),
+                Err(err) => Err(err.into()),
+            };
+            match out {
+                Ok(transferred) => log::debug!("Tunnel to {target} closed, {} bytes sent, {} bytes received", transferred.upstream, transferred.downstream),
+                Err(err) => log::error!("Error tunnelling to {target} - {err:#}"),
+            }
+        });
+        Response::new(Body::empty())
+    }
+
+    /// Proxies an absolute-form request, e.g. `GET http://host/path`
+    async fn forward(&self, req: Request<Body>) -> Response<Body> {
+        let authority = req.uri().authority().cloned()
+            .filter(|_| req.uri().scheme() == Some(&Scheme::HTTP));
+        let Some(authority) = authority else {
+            return error_response(StatusCode::BAD_REQUEST, "Only absolute http:// requests and CONNECT are supported");
+        };
+        let target = match self.resolve(&authority, authority.port_u16().unwrap_or(DEFAULT_HTTP_PORT)).await {
+            Ok(target) => target,
+            Err(response) => return response,
+        };
+
+        let (mut parts, body) = req.into_parts();
+        let path = parts.uri.path_and_query().map(|path| path.as_str()).unwrap_or("/").to_string();
+        parts.uri = match Uri::from_str(&format!("http://{target}{path}")) {
+            Ok(uri) => uri,
+            Err(_) => return error_response(StatusCode::BAD_REQUEST, "Invalid request target"),
+        };
+        parts.version = Version::HTTP_11;
+        remove_hop_by_hop(&mut parts.headers, false);
+        parts.headers.remove("proxy-connection");
+
+        log::debug!("Proxying {} {authority}{path} to {target}", parts.method);
+        match self.client.request(Request::from_parts(parts, body)).await {
+            Ok(mut response) => {
+                remove_hop_by_hop(response.headers_mut(), false);
+                response
+            }
+            Err(err) => {
+                log::error!("Error proxying request for {authority} to {target} - {err}");
+                error_response(StatusCode::BAD_GATEWAY, "Error contacting the target")
+            }
+        }
+    }
+
+    /// Names of name-mode plumbing already set up resolve to their bound address, other hosts must be allowed
+    async fn resolve(&self, authority: &Authority, port: u16) -> Result<SocketAddr, Response<Body>> {
+        let host = authority.host().trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+        if let Some(ip) = super::route_name(&self.name_resolver, &host, &self.on_demand_names) {
+            return Ok(SocketAddr::new(ip, port));
+        }
+        if !self.allowed(&host) {
+            log::debug!("Refusing request for {authority}, not in allow list");
+            return Err(error_response(StatusCode::FORBIDDEN, "Host not allowed"));
+        }
+        tokio::net::lookup_host((host.as_str(), port)).await
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| error_response(StatusCode::BAD_GATEWAY, "Could not resolve host"))
+    }
+
+    fn allowed(&self, host: &str) -> bool {
+        self.allow.iter().any(|allowed| allowed == "*" || super::host_matches(allowed, host))
+    }
+}
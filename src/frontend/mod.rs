@@ -0,0 +1,109 @@
+//! Front-ends share a single listener among the name-mode plumbing, the destination of each connection
+//! is taken from the connection itself (TLS SNI, HTTP Host header, proxy requests) and looked up with
+//! the [`NameResolver`](crate::resolver::NameResolver).
+
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::net::{Listener, PeerAddr, Stream};
+use crate::plumber::Plumber;
+use crate::resolver::NameResolver;
+
+pub mod http;
+pub mod http_proxy;
+pub mod sni;
+pub mod socks;
+
+/// Time limit to receive the data needed to route a connection
+const ROUTING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Names sent by clients only reach the plumbing already set up, unless matched by `on_demand`: setting up
+/// any requested name would let every client allocate addresses and start resources rendered from its input
+fn route_name(name_resolver: &NameResolver, name: &str, on_demand: &[String]) -> Option<IpAddr> {
+    match on_demand.iter().any(|pattern| host_matches(pattern, name)) {
+        true => name_resolver.resolve(name),
+        false => name_resolver.lookup(name),
+    }
+}
+
+/// `pattern` is either a host name or `.example.com`, matching `example.com` and its subdomains
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix('.') {
+        Some(domain) => host == domain || host.ends_with(pattern),
+        None => host == pattern,
+    }
+}
+
+/// Serves the front-end listener until the plumber is shut down
+pub(crate) fn spawn<H, F>(plumber: &Plumber, listener: Listener, handler: H)
+    where
+        H: Fn(Stream, PeerAddr) -> F + Send + 'static,
+        F: Future<Output=anyhow::Result<()>> + Send + 'static,
+{
+    let shutdown = plumber.shutdown_signal();
+    let handle = tokio::spawn(async move {
+        if let Err(err) = serve(listener, shutdown, handler).await {
+            log::error!("Error serving front-end - {err}");
+        }
+    });
+    plumber.track_frontend(handle);
+}
+
+/// Accepts connections until shutdown is requested, in-flight connections are given until the shutdown deadline to complete
+async fn serve<H, F>(listener: Listener, mut shutdown: watch::Receiver<Option<Instant>>, handler: H) -> anyhow::Result<()>
+    where
+        H: Fn(Stream, PeerAddr) -> F,
+        F: Future<Output=anyhow::Result<()>> + Send + 'static,
+{
+    let source = listener.local_addr()?;
+    let active = Arc::new(AtomicUsize::new(0));
+
+    while shutdown.borrow().is_none() {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.changed() => break,
+        };
+        let connection = handler(stream, peer.clone());
+        let active = active.clone();
+        active.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                log::error!("Error processing stream from {peer} - {err:#}");
+            }
+            active.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+
+    drop(listener);
+    let deadline = shutdown.borrow().unwrap_or_else(Instant::now);
+    while active.load(Ordering::Relaxed) > 0 {
+        if Instant::now() >= deadline {
+            log::warn!("Closing {source} with connections still in-flight");
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    log::debug!("Front-end {source} terminated");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_names_and_domains() {
+        assert!(host_matches("web.http.lo", "web.http.lo"));
+        assert!(!host_matches("web.http.lo", "api.http.lo"));
+        assert!(host_matches(".http.lo", "web.http.lo"));
+        assert!(host_matches(".http.lo", "a.web.http.lo"));
+        assert!(host_matches(".http.lo", "http.lo"));
+        assert!(!host_matches(".http.lo", "evilhttp.lo"));
+    }
+}
@@ -0,0 +1,252 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{anyhow, Context};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::config::{SniPlumbingConfig, TargetAddr};
+use crate::forward::{forward_sockets, ForwardOptions};
+use crate::net::{PeerAddr, Stream};
+use crate::plumber::Plumber;
+use crate::resolver::NameResolver;
+
+use super::ROUTING_TIMEOUT;
+
+const RECORD_HEADER_LENGTH: usize = 5;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const SERVER_NAME_HOST: u8 = 0x00;
+/// Upper bound of the buffered ClientHello, well above what real clients send
+const MAX_CLIENT_HELLO_LENGTH: usize = 64 * 1024;
+
+enum ClientHello {
+    Incomplete,
+    Complete(Option<String>),
+}
+
+/// Starts a listener routing TLS connections by their SNI to the name-mode plumbing, TLS is not terminated
+pub fn attach(plumber: &Plumber, name_resolver: NameResolver, name: &str, socket_name: &str, in_addr: IpAddr, config: SniPlumbingConfig) -> anyhow::Result<()> {
+    let source = SocketAddr::new(in_addr, config.source);
+    log::info!("Starting SNI router on {source}");
+    let listener = plumber.bind_frontend(&format!("{name}/{socket_name}"), source)?;
+    let target_port = config.target.unwrap_or(config.source);
+    let options = ForwardOptions::from(&config.connection);
+    super::spawn(plumber, listener, move |stream, peer| {
+        let name_resolver = name_resolver.clone();
+        let default_name = config.default_name.clone();
+        let on_demand_names = config.on_demand_names.clone();
+        let options = options.clone();
+        async move {
+            route(stream, peer, &name_resolver, default_name.as_deref(), &on_demand_names, target_port, &options).await
+        }
+    });
+    Ok(())
+}
+
+async fn route(mut incoming: Stream, peer: PeerAddr, name_resolver: &NameResolver, default_name: Option<&str>, on_demand_names: &[String], target_port: u16, options: &ForwardOptions) -> anyhow::Result<()> {
+    let (server_name, client_hello) = tokio::time::timeout(ROUTING_TIMEOUT, read_client_hello(&mut incoming)).await
+        .context("Timeout reading TLS ClientHello")?
+        .with_context(|| format!("Error reading TLS ClientHello from {peer}"))?;
+
+    let resolved = server_name.as_deref()
+        .and_then(|name| super::route_name(name_resolver, name, on_demand_names))
+        .or_else(|| default_name.and_then(|name| name_resolver.resolve(name)))
+        .ok_or_else(|| anyhow!("No plumbing found for server name {server_name:?}"))?;
+
+    let target = TargetAddr::Tcp(SocketAddr::new(resolved, target_port));
+    log::debug!("Routing {server_name:?} from {peer} to {target}");
+    let mut outgoing = Stream::connect(&target).await
+        .with_context(|| format!("Error connecting to address {target}"))?;
+    outgoing.write_all(&client_hello).await?;
+
+    let transferred = forward_sockets(incoming, outgoing, options).await
+        .context("Error during socket copy")?;
+    log::debug!("Connection to {target} closed, {} bytes sent, {} bytes received", transferred.upstream + client_hello.len() as u64, transferred.downstream);
+    Ok(())
+}
+
+/// Reads the records carrying the ClientHello, they are returned to be replayed to the target
+async fn read_client_hello(stream: &mut Stream) -> anyhow::Result<(Option<String>, Vec<u8>)> {
+    let mut buffer = Vec::with_capacity(1024);
+    loop {
+        if let ClientHello::Complete(server_name) = parse_client_hello(&buffer)? {
+            return Ok((server_name, buffer));
+        }
+        if buffer.len() >= MAX_CLIENT_HELLO_LENGTH {
+            return Err(anyhow!("ClientHello too long"));
+        }
+        let read = stream.read_buf(&mut buffer).await?;
+        if read == 0 {
+            return Err(anyhow!("Connection closed before the end of the ClientHello"));
+        }
+    }
+}
+
+/// The ClientHello handshake message may be fragmented across several records
+fn parse_client_hello(raw: &[u8]) -> anyhow::Result<ClientHello> {
+    let mut handshake = Vec::new();
+    let mut position = 0;
+    loop {
+        let Some(header) = raw.get(position..position + RECORD_HEADER_LENGTH) else {
+            return Ok(ClientHello::Incomplete);
+        };
+        if header[0] != CONTENT_TYPE_HANDSHAKE {
+            return Err(anyhow!("Not a TLS handshake"));
+        }
+        let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let Some(fragment) = raw.get(position + RECORD_HEADER_LENGTH..position + RECORD_HEADER_LENGTH + length) else {
+            return Ok(ClientHello::Incomplete);
+        };
+        handshake.extend_from_slice(fragment);
+        position += RECORD_HEADER_LENGTH + length;
+
+        if handshake.len() >= 4 {
+            if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+                return Err(anyhow!("First handshake message is not a ClientHello"));
+            }
+            let length = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if let Some(body) = handshake.get(4..4 + length) {
+                return Ok(ClientHello::Complete(server_name(body)));
+            }
+        }
+    }
+}
+
+/// Extracts the host name from the server_name extension, `None` when missing or malformed
+fn server_name(client_hello: &[u8]) -> Option<String> {
+    let mut reader = Reader(client_hello);
+    reader.skip(2 + 32)?; // legacy version, random
+    let session_id = reader.u8()? as usize;
+    reader.skip(session_id)?;
+    let cipher_suites = reader.u16()? as usize;
+    reader.skip(cipher_suites)?;
+    let compression_methods = reader.u8()? as usize;
+    reader.skip(compression_methods)?;
+    let extensions_length = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(extensions_length)?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let extension_length = extensions.u16()? as usize;
+        let extension = extensions.take(extension_length)?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = Reader(extension);
+        let list_length = names.u16()? as usize;
+        let mut names = Reader(names.take(list_length)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name_length = names.u16()? as usize;
+            let name = names.take(name_length)?;
+            if name_type == SERVER_NAME_HOST {
+                return std::str::from_utf8(name).ok().map(|name| name.to_ascii_lowercase());
+            }
+        }
+    }
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.0.len() < length {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn skip(&mut self, length: usize) -> Option<()> {
+        self.take(length).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ClientHello handshake message with a server_name extension when `name` is set
+    fn client_hello(name: Option<&str>) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[7; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]); // session id, cipher suites, compression
+        let mut extensions = vec![0x00, 0x0b, 0x00, 0x02, 0x01, 0x00]; // ec_point_formats
+        if let Some(name) = name {
+            let mut list = vec![SERVER_NAME_HOST];
+            list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            list.extend_from_slice(name.as_bytes());
+            extensions.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+            extensions.extend_from_slice(&(list.len() as u16 + 2).to_be_bytes());
+            extensions.extend_from_slice(&(list.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(&list);
+        }
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+        handshake
+    }
+
+    fn records(handshake: &[u8], fragment: usize) -> Vec<u8> {
+        handshake.chunks(fragment)
+            .flat_map(|chunk| {
+                let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+                record.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+                record.extend_from_slice(chunk);
+                record
+            })
+            .collect()
+    }
+
+    fn parsed_name(raw: &[u8]) -> Option<String> {
+        match parse_client_hello(raw).unwrap() {
+            ClientHello::Complete(name) => name,
+            ClientHello::Incomplete => panic!("ClientHello reported incomplete"),
+        }
+    }
+
+    #[test]
+    fn reads_server_name() {
+        let raw = records(&client_hello(Some("Web.Example.lo")), usize::MAX);
+        assert_eq!(parsed_name(&raw).as_deref(), Some("web.example.lo"));
+        let raw = records(&client_hello(None), usize::MAX);
+        assert_eq!(parsed_name(&raw), None);
+    }
+
+    #[test]
+    fn reassembles_fragmented_records() {
+        let raw = records(&client_hello(Some("web.example.lo")), 10);
+        assert_eq!(parsed_name(&raw).as_deref(), Some("web.example.lo"));
+        for length in 0..raw.len() {
+            assert!(matches!(parse_client_hello(&raw[..length]), Ok(ClientHello::Incomplete)), "prefix of {length} bytes");
+        }
+    }
+
+    #[test]
+    fn rejects_other_traffic() {
+        assert!(parse_client_hello(b"GET / HTTP/1.1\r\n\r\n").is_err());
+        let mut server_hello = client_hello(None);
+        server_hello[0] = 2;
+        assert!(parse_client_hello(&records(&server_hello, usize::MAX)).is_err());
+    }
+
+    #[test]
+    fn ignores_malformed_extensions() {
+        let name = "web.example.lo";
+        let mut handshake = client_hello(Some(name));
+        let length_offset = handshake.len() - name.len() - 1;
+        handshake[length_offset] = 0xff; // host name length past the end of the extension
+        assert_eq!(parsed_name(&records(&handshake, usize::MAX)), None);
+    }
+}
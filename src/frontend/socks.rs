@@ -0,0 +1,395 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{anyhow, Context};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+
+use crate::config::{SocksPlumbingConfig, TargetAddr, UnknownNamePolicy};
+use crate::forward::{forward_sockets, ForwardOptions};
+use crate::net::{PeerAddr, Stream};
+use crate::plumber::Plumber;
+use crate::resolver::NameResolver;
+
+use super::ROUTING_TIMEOUT;
+
+const VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+const COMMAND_CONNECT: u8 = 0x01;
+const COMMAND_UDP_ASSOCIATE: u8 = 0x03;
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+/// Largest UDP payload, datagrams are never fragmented by the relay
+const MAX_DATAGRAM_LENGTH: usize = 65_535;
+
+/// Reply codes of RFC 1928
+#[derive(Debug, Clone, Copy)]
+enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    NotAllowed = 0x02,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    CommandNotSupported = 0x07,
+    AddressNotSupported = 0x08,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Destination {
+    Ip(SocketAddr),
+    Name(String, u16),
+}
+
+#[derive(Clone)]
+struct SocksRouter {
+    name_resolver: NameResolver,
+    unknown_names: UnknownNamePolicy,
+    udp: bool,
+    on_demand_names: Vec<String>,
+    options: ForwardOptions,
+}
+
+/// Starts a SOCKS5 proxy, requested names are resolved with the name-mode plumbing
+pub fn attach(plumber: &Plumber, name_resolver: NameResolver, name: &str, socket_name: &str, in_addr: IpAddr, config: SocksPlumbingConfig) -> anyhow::Result<()> {
+    let source = SocketAddr::new(in_addr, config.source);
+    log::info!("Starting SOCKS5 proxy on {source}");
+    let listener = plumber.bind_frontend(&format!("{name}/{socket_name}"), source)?;
+    let router = SocksRouter {
+        name_resolver,
+        unknown_names: config.unknown_names,
+        udp: config.udp,
+        on_demand_names: config.on_demand_names,
+        options: ForwardOptions::from(&config.connection),
+    };
+    super::spawn(plumber, listener, move |stream, peer| {
+        let router = router.clone();
+        async move { router.serve(stream, peer).await }
+    });
+    Ok(())
+}
+
+impl SocksRouter {
+    async fn serve(&self, mut incoming: Stream, peer: PeerAddr) -> anyhow::Result<()> {
+        let (command, destination) = tokio::time::timeout(ROUTING_TIMEOUT, read_request(&mut incoming)).await
+            .context("Timeout reading SOCKS request")?
+            .with_context(|| format!("Error reading SOCKS request from {peer}"))?;
+        let Some(destination) = destination else {
+            write_reply(&mut incoming, Reply::AddressNotSupported, None).await?;
+            return Err(anyhow!("Unsupported address type requested by {peer}"));
+        };
+        match command {
+            COMMAND_CONNECT => self.connect(incoming, peer, destination).await,
+            COMMAND_UDP_ASSOCIATE if self.udp => self.associate(incoming, peer, destination).await,
+            _ => {
+                write_reply(&mut incoming, Reply::CommandNotSupported, None).await?;
+                Err(anyhow!("Unsupported SOCKS command {command:#04x} from {peer}"))
+            }
+        }
+    }
+
+    async fn connect(&self, mut incoming: Stream, peer: PeerAddr, destination: Destination) -> anyhow::Result<()> {
+        let target = match self.resolve(&destination).await {
+            Ok(target) => target,
+            Err(reply) => {
+                write_reply(&mut incoming, reply, None).await?;
+                return Err(anyhow!("Refused connection from {peer} to {destination:?} - {reply:?}"));
+            }
+        };
+        log::debug!("Routing {destination:?} from {peer} to {target}");
+        let outgoing = match Stream::connect(&TargetAddr::Tcp(target)).await {
+            Ok(outgoing) => outgoing,
+            Err(err) => {
+                let reply = match err.kind() {
+                    std::io::ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+                    _ => Reply::HostUnreachable,
+                };
+                write_reply(&mut incoming, reply, None).await?;
+                return Err(anyhow!(err).context(format!("Error connecting to address {target}")));
+            }
+        };
+        write_reply(&mut incoming, Reply::Succeeded, outgoing.local_addr()).await?;
+
+        let transferred = forward_sockets(incoming, outgoing, &self.options).await
+            .context("Error during socket copy")?;
+        log::debug!("Connection to {target} closed, {} bytes sent, {} bytes received", transferred.upstream, transferred.downstream);
+        Ok(())
+    }
+
+    /// Relays the datagrams of the client until the control connection is closed
+    async fn associate(&self, mut control: Stream, peer: PeerAddr, requested: Destination) -> anyhow::Result<()> {
+        let (Some(local), Some(peer_addr)) = (control.local_addr(), peer.socket_addr()) else {
+            write_reply(&mut control, Reply::GeneralFailure, None).await?;
+            return Err(anyhow!("UDP relay requires a TCP control connection"));
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(local.ip(), 0)).await
+            .context("Error binding UDP relay")?;
+        write_reply(&mut control, Reply::Succeeded, Some(socket.local_addr()?)).await?;
+        log::debug!("Relaying UDP for {peer} on {}", socket.local_addr()?);
+
+        // the client may announce the address it sends from, otherwise the first datagram from its ip is used
+        let mut client = match requested {
+            Destination::Ip(addr) if !addr.ip().is_unspecified() && addr.port() != 0 => Some(addr),
+            _ => None,
+        };
+        let mut resolved = HashMap::new();
+        // only replies from destinations the client sent to are relayed back
+        let mut contacted = HashSet::new();
+        let mut datagram = vec![0u8; MAX_DATAGRAM_LENGTH];
+        let mut control_buffer = [0u8; 64];
+        loop {
+            let (length, from) = tokio::select! {
+                received = socket.recv_from(&mut datagram) => received?,
+                read = control.read(&mut control_buffer) => match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => continue,
+                },
+            };
+            let from_client = match client {
+                Some(client) => from == client,
+                None => from.ip() == peer_addr.ip(),
+            };
+            if from_client {
+                client = Some(from);
+                let Some((destination, payload)) = parse_datagram(&datagram[..length]) else {
+                    log::debug!("Dropping malformed or fragmented datagram from {from}");
+                    continue;
+                };
+                let target = match &destination {
+                    Destination::Name(name, port) => match resolved.get(&(name.clone(), *port)) {
+                        Some(target) => *target,
+                        None => {
+                            let target = self.resolve_datagram(&destination).await;
+                            resolved.insert((name.clone(), *port), target);
+                            target
+                        }
+                    }
+                    Destination::Ip(_) => self.resolve_datagram(&destination).await,
+                };
+                match target {
+                    Ok(target) => {
+                        contacted.insert(target);
+                        if let Err(err) = socket.send_to(payload, target).await {
+                            log::debug!("Error relaying datagram from {from} to {target} - {err}");
+                        }
+                    }
+                    Err(reply) => log::debug!("Dropping datagram from {from} to {destination:?} - {reply:?}"),
+                }
+            } else if let Some(client) = client.filter(|_| contacted.contains(&from)) {
+                let mut relayed = vec![0, 0, 0];
+                relayed.extend(encode_address(from));
+                relayed.extend_from_slice(&datagram[..length]);
+                if let Err(err) = socket.send_to(&relayed, client).await {
+                    log::debug!("Error relaying datagram from {from} to {client} - {err}");
+                }
+            } else {
+                log::debug!("Dropping datagram from {from}, not a destination of {peer}");
+            }
+        }
+        log::debug!("UDP relay for {peer} closed");
+        Ok(())
+    }
+
+    /// Name-mode plumbing only listens on TCP, datagrams are relayed to other destinations only
+    async fn resolve_datagram(&self, destination: &Destination) -> Result<SocketAddr, Reply> {
+        match destination {
+            Destination::Name(name, _) if self.name_resolver.manages(name) => Err(Reply::NotAllowed),
+            _ => self.resolve(destination).await,
+        }
+    }
+
+    /// Names of name-mode plumbing resolve to their bound address, other destinations follow the policy
+    async fn resolve(&self, destination: &Destination) -> Result<SocketAddr, Reply> {
+        match destination {
+            Destination::Name(name, port) => {
+                if let Some(ip) = super::route_name(&self.name_resolver, name, &self.on_demand_names) {
+                    return Ok(SocketAddr::new(ip, *port));
+                }
+                if self.unknown_names == UnknownNamePolicy::Reject {
+                    return Err(Reply::NotAllowed);
+                }
+                tokio::net::lookup_host((name.as_str(), *port)).await
+                    .ok()
+                    .and_then(|mut addrs| addrs.next())
+                    .ok_or(Reply::HostUnreachable)
+            }
+            Destination::Ip(addr) => match self.unknown_names {
+                UnknownNamePolicy::Reject => Err(Reply::NotAllowed),
+                UnknownNamePolicy::PassThrough => Ok(*addr),
+            },
+        }
+    }
+}
+
+/// Negotiates the authentication method and reads the request, `None` is returned for unknown address types
+async fn read_request<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> anyhow::Result<(u8, Option<Destination>)> {
+    let [version, methods] = read_array(stream).await?;
+    if version != VERSION {
+        return Err(anyhow!("Unsupported SOCKS version {version}"));
+    }
+    let mut offered = vec![0u8; methods as usize];
+    stream.read_exact(&mut offered).await?;
+    if !offered.contains(&METHOD_NO_AUTH) {
+        stream.write_all(&[VERSION, METHOD_NONE_ACCEPTABLE]).await?;
+        return Err(anyhow!("Client requires authentication"));
+    }
+    stream.write_all(&[VERSION, METHOD_NO_AUTH]).await?;
+
+    let [version, command, _reserved, address_type] = read_array(stream).await?;
+    if version != VERSION {
+        return Err(anyhow!("Unsupported SOCKS version {version}"));
+    }
+    let destination = match address_type {
+        ADDRESS_IPV4 => {
+            let ip = Ipv4Addr::from(read_array::<4, _>(stream).await?);
+            Some(Destination::Ip(SocketAddr::new(ip.into(), stream.read_u16().await?)))
+        }
+        ADDRESS_IPV6 => {
+            let ip = Ipv6Addr::from(read_array::<16, _>(stream).await?);
+            Some(Destination::Ip(SocketAddr::new(ip.into(), stream.read_u16().await?)))
+        }
+        ADDRESS_DOMAIN => {
+            let mut name = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut name).await?;
+            let name = String::from_utf8(name).context("Invalid domain name")?;
+            Some(Destination::Name(name.to_ascii_lowercase(), stream.read_u16().await?))
+        }
+        _ => None,
+    };
+    Ok((command, destination))
+}
+
+async fn read_array<const N: usize, R: AsyncRead + Unpin>(stream: &mut R) -> std::io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    stream.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+async fn write_reply(stream: &mut Stream, reply: Reply, bound: Option<SocketAddr>) -> std::io::Result<()> {
+    let mut message = vec![VERSION, reply as u8, 0];
+    message.extend(encode_address(bound.unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)))));
+    stream.write_all(&message).await
+}
+
+fn encode_address(addr: SocketAddr) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(19);
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            encoded.push(ADDRESS_IPV4);
+            encoded.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            encoded.push(ADDRESS_IPV6);
+            encoded.extend_from_slice(&ip.octets());
+        }
+    }
+    encoded.extend_from_slice(&addr.port().to_be_bytes());
+    encoded
+}
+
+/// Splits a relayed datagram into its destination and payload, fragments are not supported
+fn parse_datagram(datagram: &[u8]) -> Option<(Destination, &[u8])> {
+    let (header, rest) = datagram.split_first_chunk::<4>()?;
+    let [_, _, fragment, address_type] = *header;
+    if fragment != 0 {
+        return None;
+    }
+    let (destination, rest) = match address_type {
+        ADDRESS_IPV4 => {
+            let (ip, rest) = rest.split_first_chunk::<4>()?;
+            let (port, rest) = rest.split_first_chunk::<2>()?;
+            (Destination::Ip(SocketAddr::new(Ipv4Addr::from(*ip).into(), u16::from_be_bytes(*port))), rest)
+        }
+        ADDRESS_IPV6 => {
+            let (ip, rest) = rest.split_first_chunk::<16>()?;
+            let (port, rest) = rest.split_first_chunk::<2>()?;
+            (Destination::Ip(SocketAddr::new(Ipv6Addr::from(*ip).into(), u16::from_be_bytes(*port))), rest)
+        }
+        ADDRESS_DOMAIN => {
+            let (length, rest) = rest.split_first()?;
+            let name = rest.get(..*length as usize)?;
+            let (port, rest) = rest[*length as usize..].split_first_chunk::<2>()?;
+            let name = std::str::from_utf8(name).ok()?.to_ascii_lowercase();
+            (Destination::Name(name, u16::from_be_bytes(*port)), rest)
+        }
+        _ => return None,
+    };
+    Some((destination, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `read_request` on the bytes sent by a client, along with the bytes written back to it
+    async fn request(client: &[u8]) -> (anyhow::Result<(u8, Option<Destination>)>, Vec<u8>) {
+        let (mut near, mut far) = tokio::io::duplex(1024);
+        near.write_all(client).await.unwrap();
+        let res = read_request(&mut far).await;
+        drop(far);
+        let mut written = Vec::new();
+        near.read_to_end(&mut written).await.unwrap();
+        (res, written)
+    }
+
+    #[tokio::test]
+    async fn reads_connect_requests() {
+        let (res, written) = request(b"\x05\x01\x00\x05\x01\x00\x03\x0bWeb.Http.lo\x01\xbb").await;
+        assert_eq!(res.unwrap(), (COMMAND_CONNECT, Some(Destination::Name("web.http.lo".to_string(), 443))));
+        assert_eq!(written, [VERSION, METHOD_NO_AUTH]);
+
+        let (res, _) = request(b"\x05\x02\x02\x00\x05\x01\x00\x01\x7f\x00\x00\x01\x00\x50").await;
+        assert_eq!(res.unwrap(), (COMMAND_CONNECT, Some(Destination::Ip("127.0.0.1:80".parse().unwrap()))));
+
+        let mut ipv6 = b"\x05\x01\x00\x05\x03\x00\x04".to_vec();
+        ipv6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        ipv6.extend_from_slice(&53u16.to_be_bytes());
+        let (res, _) = request(&ipv6).await;
+        assert_eq!(res.unwrap(), (COMMAND_UDP_ASSOCIATE, Some(Destination::Ip("[::1]:53".parse().unwrap()))));
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported_requests() {
+        let (res, written) = request(b"\x05\x01\x02").await;
+        assert!(res.is_err());
+        assert_eq!(written, [VERSION, METHOD_NONE_ACCEPTABLE]);
+
+        let (res, written) = request(b"\x04\x01\x00").await;
+        assert!(res.is_err());
+        assert!(written.is_empty());
+
+        let (res, _) = request(b"\x05\x01\x00\x05\x01\x00\x09").await;
+        assert_eq!(res.unwrap(), (COMMAND_CONNECT, None));
+    }
+
+    #[test]
+    fn parses_datagrams() {
+        let mut datagram = vec![0, 0, 0];
+        datagram.extend(encode_address("10.0.0.1:53".parse().unwrap()));
+        datagram.extend_from_slice(b"query");
+        let (destination, payload) = parse_datagram(&datagram).unwrap();
+        assert_eq!(destination, Destination::Ip("10.0.0.1:53".parse().unwrap()));
+        assert_eq!(payload, b"query");
+
+        let (destination, payload) = parse_datagram(b"\x00\x00\x00\x03\x06DNS.lo\x00\x35").unwrap();
+        assert_eq!(destination, Destination::Name("dns.lo".to_string(), 53));
+        assert!(payload.is_empty());
+    }
+
+    #[test]
+    fn rejects_fragmented_or_truncated_datagrams() {
+        assert!(parse_datagram(b"\x00\x00\x01\x01\x7f\x00\x00\x01\x00\x35data").is_none());
+        assert!(parse_datagram(b"\x00\x00\x00\x01\x7f\x00\x00").is_none());
+        assert!(parse_datagram(b"\x00\x00\x00\x03\x09short\x00\x35").is_none());
+        assert!(parse_datagram(b"\x00\x00\x00\x02").is_none());
+    }
+
+    #[test]
+    fn encodes_addresses() {
+        assert_eq!(encode_address("10.0.0.1:80".parse().unwrap()), [ADDRESS_IPV4, 10, 0, 0, 1, 0, 80]);
+        let encoded = encode_address("[::1]:80".parse().unwrap());
+        assert_eq!(encoded.len(), 19);
+        assert_eq!(encoded[0], ADDRESS_IPV6);
+    }
+}
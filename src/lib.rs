@@ -1,11 +1,27 @@
-mod config;
+pub mod config;
 mod utils;
 mod runner;
 mod cmd_resource;
-mod args;
 mod connections_counter;
-mod plumber;
+pub mod plumber;
 pub mod api;
 mod ext;
-mod resolver;
-mod healthcheck;
\ No newline at end of file
+pub mod resolver;
+mod healthcheck;
+mod cgroup;
+mod credentials;
+pub mod privileges;
+pub mod systemd;
+pub mod forward;
+pub mod net;
+mod balancer;
+mod limiter;
+mod shaping;
+mod faults;
+mod capture;
+mod session;
+mod mirror;
+pub mod access_log;
+mod proxy_protocol;
+pub mod tls;
+pub mod frontend;
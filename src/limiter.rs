@@ -0,0 +1,141 @@
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
+
+use crate::config::ConnectionConfig;
+
+/// Caps the concurrent connections, connections over the limit wait in a bounded queue or are refused
+pub struct ConnectionLimiter {
+    semaphore: Option<Arc<Semaphore>>,
+    queue_length: usize,
+    queue_timeout: Duration,
+    active: AtomicUsize,
+    queued: AtomicUsize,
+}
+
+/// Slot held for the lifetime of a connection
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Releases the queue slot even when the waiting connection is dropped
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl ConnectionLimiter {
+    /// Without `max_connections` connections are only counted
+    pub fn new(max_connections: Option<NonZeroUsize>, config: &ConnectionConfig) -> Self {
+        Self {
+            semaphore: max_connections.map(|max| Arc::new(Semaphore::new(max.get()))),
+            queue_length: config.queue_length,
+            queue_timeout: Duration::from_millis(config.queue_timeout_millis),
+            active: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    pub async fn acquire(self: &Arc<Self>) -> anyhow::Result<ConnectionPermit> {
+        let permit = match &self.semaphore {
+            None => None,
+            Some(semaphore) => match semaphore.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(TryAcquireError::NoPermits) => Some(self.wait(semaphore.clone()).await?),
+                Err(TryAcquireError::Closed) => return Err(anyhow!("Connection limiter closed")),
+            },
+        };
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Ok(ConnectionPermit { limiter: self.clone(), _permit: permit })
+    }
+
+    async fn wait(&self, semaphore: Arc<Semaphore>) -> anyhow::Result<OwnedSemaphorePermit> {
+        if self.queued.fetch_add(1, Ordering::Relaxed) >= self.queue_length {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            return Err(anyhow!("Connection limit reached"));
+        }
+        let _slot = QueueSlot(&self.queued);
+        tokio::time::timeout(self.queue_timeout, semaphore.acquire_owned()).await
+            .map_err(|_| anyhow!("Timeout waiting for a connection slot"))?
+            .map_err(|_| anyhow!("Connection limiter closed"))
+    }
+
+    /// Connections currently holding a slot
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Connections waiting for a slot
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.limiter.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max_connections: Option<usize>, queue_length: usize) -> Arc<ConnectionLimiter> {
+        let config = ConnectionConfig { queue_length, queue_timeout_millis: 500, ..ConnectionConfig::default() };
+        Arc::new(ConnectionLimiter::new(max_connections.and_then(NonZeroUsize::new), &config))
+    }
+
+    #[tokio::test]
+    async fn refuses_at_once_without_queue() {
+        let limiter = limiter(Some(1), 0);
+        let _permit = limiter.acquire().await.unwrap();
+        assert!(limiter.acquire().await.is_err());
+        assert_eq!((limiter.active(), limiter.queued()), (1, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hands_freed_slot_to_queued_connection() {
+        let limiter = limiter(Some(1), 1);
+        let permit = limiter.acquire().await.unwrap();
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire().await.map(|_permit| ()) }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!((limiter.active(), limiter.queued()), (1, 1));
+        // the queue is full
+        assert!(limiter.acquire().await.is_err());
+
+        drop(permit);
+        waiting.await.unwrap().unwrap();
+        assert_eq!((limiter.active(), limiter.queued()), (0, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_queue_timeout() {
+        let limiter = limiter(Some(1), 1);
+        let _permit = limiter.acquire().await.unwrap();
+        let start = tokio::time::Instant::now();
+        assert!(limiter.acquire().await.is_err());
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+        assert_eq!((limiter.active(), limiter.queued()), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn counts_connections_without_limit() {
+        let limiter = limiter(None, 0);
+        let permits: Vec<_> = futures::future::try_join_all((0..3).map(|_| limiter.acquire())).await.unwrap();
+        assert_eq!((limiter.active(), limiter.queued()), (3, 0));
+        drop(permits);
+        assert_eq!(limiter.active(), 0);
+    }
+}
@@ -1,38 +1,28 @@
 use std::collections::BTreeMap;
-use std::fmt::Display;
 use std::fs;
-use std::future::Future;
 use std::net::IpAddr;
-use std::ops::Add;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 use anyhow::Context;
 
 use clap::Parser;
-use futures::future::Either;
-use futures::lock::Mutex;
-use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
-use crate::api::build_server;
+use port_plumber::access_log::AccessLog;
+use port_plumber::api::build_server;
+use port_plumber::frontend::{http, http_proxy, sni, socks};
+use port_plumber::config::{ConnectionConfig, NamePlumbingConfig, PlumbingItemConfig, PortPlumberConfig, ResourceConfig, SocketConf, TargetAddr};
+use port_plumber::plumber::{Plumber, PlumbingDescriptor, PlumbingTarget};
+use port_plumber::privileges::drop_privileges;
+use port_plumber::resolver::NameResolver;
+use port_plumber::systemd;
+use port_plumber::systemd::ListenFds;
+use port_plumber::tls::{self, LocalCa};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::Instant;
 
 use crate::args::PortPlumberArgs;
-use crate::cmd_resource::CmdResource;
-use crate::config::{NamePlumbingConfig, PlumbingItemConfig, PortPlumberConfig, SocketConf};
-use crate::connections_counter::ConnectionCounter;
-use crate::plumber::{Plumber, PlumbingDescriptor};
-use crate::resolver::NameResolver;
-
-mod config;
-mod utils;
-mod runner;
-mod cmd_resource;
+
 mod args;
-mod connections_counter;
-mod api;
-mod plumber;
-mod ext;
-mod resolver;
-mod healthcheck;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
@@ -49,44 +39,147 @@ async fn main() -> anyhow::Result<()> {
         .context("Error loading config file")?;
     let config: PortPlumberConfig = toml::from_str(&config_content)
         .context("Error parsing config file")?;
+    for (name, plumbing) in &config.plumbing {
+        for (socket_name, connection) in connections(plumbing) {
+            connection.validate()
+                .with_context(|| format!("Invalid configuration of {name}/{socket_name}"))?;
+        }
+        for (socket_name, resource) in resources(plumbing) {
+            resource.validate()
+                .and_then(|_| match config.run_as {
+                    Some(_) => resource.validate_unprivileged(),
+                    None => Ok(()),
+                })
+                .with_context(|| format!("Invalid resource of {name}/{socket_name}"))?;
+        }
+    }
+
+    let cmd_path = std::env::var("CMD_SOCKET")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or(config.socket);
 
+    let mut listen_fds = ListenFds::from_env();
+    let inherited_control = cmd_path.as_ref()
+        .map(|path| listen_fds.take_unix("control", path))
+        .transpose()?
+        .flatten();
 
     let plumber = Plumber::new();
+    plumber.inherit(listen_fds);
+    if config.plumbing.values().any(uses_local_ca) {
+        let ca_dir = config.tls_ca_dir.clone()
+            .or_else(tls::default_ca_dir)
+            .context("Could not determine local CA directory")?;
+        plumber.use_local_ca(LocalCa::load_or_create(&ca_dir)?);
+    }
     let mut resolv_conf: BTreeMap<String, SocketConf<NamePlumbingConfig>> = BTreeMap::new();
+    let mut sni_conf = Vec::new();
+    let mut http_conf = Vec::new();
+    let mut socks_conf = Vec::new();
+    let mut http_proxy_conf = Vec::new();
 
     for (name, plumbing) in config.plumbing {
         match plumbing {
             PlumbingItemConfig::Addr(conf) => {
                 let in_addr: IpAddr = name.parse()?;
-                for (_name, socket) in conf.sockets {
+                let access_log = conf.access_log.as_ref()
+                    .map(AccessLog::open)
+                    .transpose()
+                    .with_context(|| format!("Error configuring access log for {name}"))?
+                    .map(Arc::new);
+                for (socket_name, socket) in conf.sockets {
+                    let targets = socket.listener_targets()
+                        .with_context(|| format!("Invalid targets for {name}/{socket_name}"))?;
                     plumber.attach(&name, PlumbingDescriptor {
+                        socket_name,
                         in_addr: Some(in_addr),
-                        in_port: socket.source,
-                        out_addr: Some(socket.target.ip()),
-                        out_port: socket.target.port(),
+                        out_addr: targets.iter().flatten().find_map(|target| match target {
+                            TargetAddr::Tcp(addr) => Some(addr.ip()),
+                            TargetAddr::Unix(_) => None,
+                        }),
+                        source: socket.source,
+                        socket_mode: socket.socket_mode,
+                        target: PlumbingTarget::Fixed(targets),
+                        balance: socket.balance,
                         resource: socket.resource,
+                        connection: socket.connection,
+                        access_log: access_log.clone(),
                     })?;
                 }
             }
             PlumbingItemConfig::Name(conf) => {
                 resolv_conf.insert(name, conf);
             },
+            PlumbingItemConfig::Sni(conf) => {
+                reject_access_log(&name, &conf)?;
+                sni_conf.push((name, conf));
+            }
+            PlumbingItemConfig::Http(conf) => {
+                reject_access_log(&name, &conf)?;
+                http_conf.push((name, conf));
+            }
+            PlumbingItemConfig::Socks(conf) => {
+                reject_access_log(&name, &conf)?;
+                socks_conf.push((name, conf));
+            }
+            PlumbingItemConfig::HttpProxy(conf) => {
+                reject_access_log(&name, &conf)?;
+                http_proxy_conf.push((name, conf));
+            }
         }
     }
 
-    let name_resolver = NameResolver::new(resolv_conf, plumber.clone());
+    // lazily bound name-mode listeners on privileged ports need to survive the privilege drop
+    let needs_bind_capability = resolv_conf.values()
+        .any(|conf| conf.sockets.values().any(|socket| socket.source < 1024));
+    let name_resolver = NameResolver::new(resolv_conf, plumber.clone())?;
 
-    let cmd_path = std::env::var("CMD_SOCKET")
-        .ok()
-        .filter(|v| !v.is_empty())
-        .map(PathBuf::from)
-        .or(config.socket);
+    for (name, conf) in sni_conf {
+        let in_addr: IpAddr = name.parse()?;
+        for (socket_name, socket) in conf.sockets {
+            sni::attach(&plumber, name_resolver.clone(), &name, &socket_name, in_addr, socket)?;
+        }
+    }
+    for (name, conf) in http_conf {
+        let in_addr: IpAddr = name.parse()?;
+        for (socket_name, socket) in conf.sockets {
+            http::attach(&plumber, name_resolver.clone(), &name, &socket_name, in_addr, socket)?;
+        }
+    }
+    for (name, conf) in socks_conf {
+        let in_addr: IpAddr = name.parse()?;
+        for (socket_name, socket) in conf.sockets {
+            socks::attach(&plumber, name_resolver.clone(), &name, &socket_name, in_addr, socket)?;
+        }
+    }
+    for (name, conf) in http_proxy_conf {
+        let in_addr: IpAddr = name.parse()?;
+        for (socket_name, socket) in conf.sockets {
+            http_proxy::attach(&plumber, name_resolver.clone(), &name, &socket_name, in_addr, socket)?;
+        }
+    }
 
-    if let Some(ref socket) =  cmd_path {
+    let owns_control_socket = inherited_control.is_none();
+    let server = if let Some(ref socket) = cmd_path {
         log::debug!("Starting socket server {socket:?}");
-        let server = build_server(socket, name_resolver)
+        let server = build_server(socket, inherited_control, name_resolver, plumber.clone())
             .context("Error building server")?;
         log::debug!("Socket server built");
+        Some(server)
+    } else {
+        None
+    };
+
+    if let Some(ref run_as) = config.run_as {
+        let listener_paths = plumber.owned_paths();
+        let owned_paths: Vec<&Path> = cmd_path.iter().chain(&listener_paths).map(PathBuf::as_path).collect();
+        drop_privileges(run_as, needs_bind_capability, &owned_paths)
+            .context("Error dropping privileges")?;
+    }
+
+    if let Some(server) = server {
         tokio::spawn(async move {
             log::debug!("Spawning socket server");
             let out = server.await;
@@ -96,11 +189,44 @@ async fn main() -> anyhow::Result<()> {
         });
     }
 
+    let sockets_count: usize = plumber.list().iter().map(|entry| entry.sockets.len()).sum();
+    systemd::notify(&format!("READY=1\nSTATUS=Listening on {sockets_count} sockets"));
+    systemd::spawn_watchdog();
+
+    let shutdown_timeout = Duration::from_millis(config.shutdown_timeout_millis);
+    let stopping_plumber = plumber.clone();
+    tokio::spawn(async move {
+        match wait_termination().await {
+            Ok(signal) => log::info!("Received {signal}, shutting down"),
+            Err(err) => log::error!("Error listening for signals, shutting down - {err}"),
+        }
+        systemd::notify("STOPPING=1");
+        stopping_plumber.shutdown(Instant::now() + shutdown_timeout);
+    });
+
     //futures::future::try_join_all(config.plumbing.into_iter().map(|(addr, conf)| listen_address(addr, conf))).await?;
     plumber.join().await?;
+
+    // an inherited control socket belongs to the service manager and must survive restarts
+    if let (Some(socket), true) = (cmd_path, owns_control_socket) {
+        if let Err(err) = fs::remove_file(&socket) {
+            log::warn!("Error removing control socket {socket:?} - {err}");
+        }
+    }
+    log::info!("Shutdown completed");
     Ok(())
 }
 
+async fn wait_termination() -> anyhow::Result<&'static str> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let name = tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    };
+    Ok(name)
+}
+
 fn config_from_user_dir() -> anyhow::Result<PathBuf> {
     let Some(config_base_path) = dirs::config_dir() else {
         anyhow::bail!("Could not find os config dir")
@@ -112,3 +238,42 @@ fn config_from_user_dir() -> anyhow::Result<PathBuf> {
         Ok(config_file_path)
     }
 }
+
+fn uses_local_ca(plumbing: &PlumbingItemConfig) -> bool {
+    connections(plumbing).into_iter()
+        .any(|(_, connection)| connection.tls.as_ref().map(|tls| tls.uses_local_ca()).unwrap_or(false))
+}
+
+/// Connection settings of the sockets of a plumbing, along with the socket names
+fn connections(plumbing: &PlumbingItemConfig) -> Vec<(&str, &ConnectionConfig)> {
+    match plumbing {
+        PlumbingItemConfig::Addr(conf) => conf.sockets.iter().map(|(name, socket)| (name.as_str(), &socket.connection)).collect(),
+        PlumbingItemConfig::Name(conf) => conf.sockets.iter().map(|(name, socket)| (name.as_str(), &socket.connection)).collect(),
+        PlumbingItemConfig::Sni(_)
+        | PlumbingItemConfig::Http(_)
+        | PlumbingItemConfig::Socks(_)
+        | PlumbingItemConfig::HttpProxy(_) => Vec::new(),
+    }
+}
+
+/// Resources of the sockets of a plumbing, along with the socket names
+fn resources(plumbing: &PlumbingItemConfig) -> Vec<(&str, &ResourceConfig)> {
+    match plumbing {
+        PlumbingItemConfig::Addr(conf) => conf.sockets.iter()
+            .filter_map(|(name, socket)| socket.resource.as_ref().map(|resource| (name.as_str(), resource)))
+            .collect(),
+        PlumbingItemConfig::Name(conf) => conf.sockets.iter().map(|(name, socket)| (name.as_str(), &socket.resource)).collect(),
+        PlumbingItemConfig::Sni(_)
+        | PlumbingItemConfig::Http(_)
+        | PlumbingItemConfig::Socks(_)
+        | PlumbingItemConfig::HttpProxy(_) => Vec::new(),
+    }
+}
+
+/// Front-ends relay connections to the name-mode plumbing, which logs them
+fn reject_access_log<T>(name: &str, conf: &SocketConf<T>) -> anyhow::Result<()> {
+    match conf.access_log {
+        Some(_) => anyhow::bail!("Access log of {name} not supported, configure it on the name-mode plumbing"),
+        None => Ok(()),
+    }
+}
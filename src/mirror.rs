@@ -0,0 +1,110 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use anyhow::Context as _;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+
+use crate::config::{MirrorConfig, TargetAddr};
+use crate::net::Stream;
+
+/// Connection to the mirror target fed with a copy of the client data, run in the background
+pub struct MirrorConnection {
+    target: TargetAddr,
+    /// Dropped once the mirrored connection is abandoned
+    sender: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    /// Bytes queued and not yet written to the mirror target
+    pending: Arc<AtomicUsize>,
+    buffer_bytes: usize,
+}
+
+/// Stream copying the data read from it to a mirror connection
+pub struct MirroredStream<S> {
+    inner: S,
+    mirror: MirrorConnection,
+}
+
+impl MirrorConnection {
+    /// Connects to the mirror target in the background, data is queued meanwhile
+    pub fn open(config: &MirrorConfig) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let target = config.target.clone();
+        let task_pending = pending.clone();
+        tokio::spawn(async move {
+            if let Err(err) = mirror(&target, receiver, task_pending).await {
+                log::warn!("Error mirroring connection to {target} - {err:#}");
+            }
+        });
+        Self { target: config.target.clone(), sender: Some(sender), pending, buffer_bytes: config.buffer_bytes }
+    }
+
+    pub fn stream<S>(self, inner: S) -> MirroredStream<S> {
+        MirroredStream { inner, mirror: self }
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if self.pending.fetch_add(data.len(), Ordering::Relaxed) + data.len() > self.buffer_bytes {
+            log::warn!("Mirror target {} too slow, mirrored connection abandoned", self.target);
+            self.sender = None;
+        } else if sender.send(data.to_vec()).is_err() {
+            // the mirror task already failed and logged the error
+            self.sender = None;
+        }
+    }
+}
+
+async fn mirror(target: &TargetAddr, mut receiver: mpsc::UnboundedReceiver<Vec<u8>>, pending: Arc<AtomicUsize>) -> anyhow::Result<()> {
+    let stream = Stream::connect(target).await
+        .context("Error connecting to mirror target")?;
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let send = async {
+        while let Some(data) = receiver.recv().await {
+            writer.write_all(&data).await?;
+            pending.fetch_sub(data.len(), Ordering::Relaxed);
+        }
+        writer.shutdown().await
+    };
+    // responses are read so that the mirror target is never blocked writing them
+    let mut discarded = tokio::io::sink();
+    tokio::select! {
+        res = send => res.context("Error sending to mirror target"),
+        res = tokio::io::copy(&mut reader, &mut discarded) => match res {
+            Ok(_) => Ok(()),
+            Err(err) => Err(err).context("Error reading from mirror target"),
+        },
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MirroredStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let read = &buf.filled()[before..];
+        if !read.is_empty() {
+            this.mirror.send(read);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MirroredStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
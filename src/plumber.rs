@@ -1,27 +1,57 @@
-use std::fmt::{Debug, Display};
+use std::fs;
 use std::future::Future;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Add;
-use std::sync::{Arc, Mutex};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, SystemTime};
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
 
 use dashmap::DashMap;
-use dashmap::mapref::multiple::RefMulti;
-use futures::future::Either;
-use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
 
-use crate::cmd_resource::CmdResource;
-use crate::config::{PlumbingItemConfig, ResourceConfig};
+use crate::access_log::{AccessLog, CloseReason, ConnectionReport, SocketAccessLog};
+use crate::balancer::{Balancer, Lease};
+use crate::capture::CaptureSwitch;
+use crate::cgroup::{CgroupUsage, ResourceCgroup};
+use crate::cmd_resource::{CmdResource, SharedResource};
+use crate::config::{BalanceConfig, ConnectionConfig, FaultConfig, MirrorConfig, ProxyProtocolVersion, ResourceConfig, SessionMode, SourceAddr, TargetAddr};
 use crate::connections_counter::ConnectionCounter;
 use crate::ext::addr::Increment;
+use crate::forward::{self, forward, forward_sockets, ForwardOptions, Progress};
+use crate::faults::{self, FaultInjector};
+use crate::limiter::ConnectionLimiter;
+use crate::mirror::MirrorConnection;
+use crate::shaping::{Direction, ShapedStream, Shaper};
+use crate::net::{BoxedStream, Listener, PeerAddr, Stream};
+use crate::proxy_protocol::{self, ProxiedAddrs};
+use crate::session::{Recorder, Replay};
+use crate::systemd::ListenFds;
+use crate::tls::{self, LocalCa, TargetTls};
+
+/// Upper bound of the delay between connect retries, so that a target coming up is reached shortly after
+/// even with many retries configured
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(5);
+/// Time limit to receive the PROXY protocol header and to complete TLS handshakes
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Clone)]
 pub struct Plumber {
     in_range: Arc<Mutex<IpAddr>>,
     out_range: Arc<Mutex<IpAddr>>,
-    plumbing: Arc<DashMap<String, Plumbing>>
+    plumbing: Arc<DashMap<String, Plumbing>>,
+    inherited: Arc<Mutex<ListenFds>>,
+    local_ca: Arc<OnceLock<LocalCa>>,
+    frontends: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    shutdown: Arc<watch::Sender<Option<Instant>>>,
 }
 
 struct Plumbing {
@@ -29,10 +59,29 @@ struct Plumbing {
     out_addr: IpAddr,
     sockets: Vec<MappedSocket>,
 }
+
+/// Listeners of a socket, one per port of a port range, sharing the same resource
 struct MappedSocket {
-    in_port: u16,
-    out_port: u16,
-    handle: JoinHandle<()>,
+    name: String,
+    source: SourceAddr,
+    balancers: Vec<Arc<Balancer>>,
+    shared: SocketShared,
+    cgroup: Option<Arc<ResourceCgroup>>,
+    handles: Vec<JoinHandle<()>>,
+    shaper: Arc<Shaper>,
+    faults: Arc<FaultInjector>,
+    capture: Arc<CaptureSwitch>,
+    /// Socket files created by the listeners, removed on termination
+    owned_paths: Vec<PathBuf>,
+}
+
+/// State shared by the listeners of a socket
+#[derive(Clone)]
+struct SocketShared {
+    resource: SharedResource,
+    counter: Arc<tokio::sync::Mutex<ConnectionCounter>>,
+    /// Connection limit of the socket and of its resource, over all the listeners
+    limiter: Arc<ConnectionLimiter>,
 }
 
 pub struct AddressBinding {
@@ -40,13 +89,91 @@ pub struct AddressBinding {
     pub target: IpAddr
 }
 
+pub struct PlumbingSummary {
+    pub name: String,
+    pub source: IpAddr,
+    pub sockets: Vec<SocketSummary>,
+}
+
+pub struct SocketSummary {
+    pub source: String,
+    pub targets: Vec<String>,
+    pub usage: Option<CgroupUsage>,
+    /// Connections being forwarded
+    pub connections: usize,
+    /// Connections waiting for a slot
+    pub queued: usize,
+}
+
 #[derive(Debug)]
 pub struct PlumbingDescriptor {
+    pub socket_name: String,
     pub in_addr: Option<IpAddr>,
-    pub in_port: u16,
+    pub source: SourceAddr,
+    /// Permissions of the socket file when listening on a unix socket
+    pub socket_mode: Option<u32>,
     pub out_addr: Option<IpAddr>,
-    pub out_port: u16,
+    pub target: PlumbingTarget,
+    pub balance: BalanceConfig,
     pub resource: Option<ResourceConfig>,
+    pub connection: ConnectionConfig,
+    /// Log shared by the sockets of the plumbing
+    pub access_log: Option<Arc<AccessLog>>,
+}
+
+/// Policy applied when setting up the connection between a client and a target
+#[derive(Clone)]
+struct ConnectOptions {
+    retries: u32,
+    backoff: Duration,
+    deadline: Option<Duration>,
+    accept_proxy: bool,
+    send_proxy: Option<ProxyProtocolVersion>,
+    tls_acceptor: Option<TlsAcceptor>,
+    target_tls: Option<TargetTls>,
+    shaper: Arc<Shaper>,
+    faults: Arc<FaultInjector>,
+    capture: Arc<CaptureSwitch>,
+    recorder: Option<Arc<Recorder>>,
+    /// Recorded sessions answering the clients, the target is not contacted
+    replay: Option<Arc<Replay>>,
+    mirror: Option<MirrorConfig>,
+    access_log: Option<SocketAccessLog>,
+}
+
+impl From<&ConnectionConfig> for ConnectOptions {
+    fn from(value: &ConnectionConfig) -> Self {
+        Self {
+            retries: value.connect_retries,
+            backoff: Duration::from_millis(value.connect_backoff_millis),
+            deadline: value.connect_deadline_millis.map(Duration::from_millis),
+            accept_proxy: value.accept_proxy_protocol,
+            send_proxy: value.send_proxy_protocol,
+            tls_acceptor: None,
+            target_tls: None,
+            shaper: Arc::new(Shaper::new(value.shaping)),
+            faults: Arc::new(FaultInjector::new(value.faults)),
+            capture: Arc::new(CaptureSwitch::new()),
+            recorder: None,
+            replay: None,
+            mirror: value.mirror.clone(),
+            access_log: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PlumbingTarget {
+    /// Port on the target address allocated to the plumbing
+    Allocated(u16),
+    /// Targets of each listener of the source, one per port of a port range
+    Fixed(Vec<Vec<TargetAddr>>),
+}
+
+impl Default for Plumber {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Plumber {
@@ -55,6 +182,22 @@ impl Plumber {
             in_range: Arc::new(Mutex::new(IpAddr::from([127, 127, 0, 0]))),
             out_range: Arc::new(Mutex::new(IpAddr::from([127, 191, 0, 0]))),
             plumbing: Default::default(),
+            inherited: Default::default(),
+            local_ca: Default::default(),
+            frontends: Default::default(),
+            shutdown: Arc::new(watch::channel(None).0),
+        }
+    }
+
+    /// Sockets received through systemd socket activation are used instead of binding new ones
+    pub fn inherit(&self, fds: ListenFds) {
+        *self.inherited.lock().expect("Broken inherited mutex") = fds;
+    }
+
+    /// Certificates of TLS listeners without a configured certificate are issued by `ca`
+    pub fn use_local_ca(&self, ca: LocalCa) {
+        if self.local_ca.set(ca).is_err() {
+            log::warn!("Local CA already configured");
         }
     }
 
@@ -66,7 +209,12 @@ impl Plumber {
         }
     }
 
-    fn resolve_plumbing(&self, name: &str, in_addr: Option<IpAddr>, out_addr: Option<IpAddr>) -> dashmap::mapref::one::RefMut<String, Plumbing> {
+    /// Source address of the plumbing `name`, when already set up
+    pub fn lookup(&self, name: &str) -> Option<IpAddr> {
+        self.plumbing.get(name).map(|plumbing| plumbing.in_addr)
+    }
+
+    fn resolve_plumbing(&self, name: &str, in_addr: Option<IpAddr>, out_addr: Option<IpAddr>) -> dashmap::mapref::one::RefMut<'_, String, Plumbing> {
         self.plumbing
             .entry(String::from(name))
             .or_insert_with(|| Plumbing {
@@ -81,32 +229,266 @@ impl Plumber {
         log::debug!("attach: {descriptor:?}");
         let mut entry = self.resolve_plumbing(name, descriptor.in_addr, descriptor.out_addr);
         log::debug!("entry: {} -> {}", entry.in_addr, entry.out_addr);
-        if let Some(plumbing) = entry.sockets.iter().find(|s| s.in_port == descriptor.in_port) {
-            log::debug!("Plumbing already defined for {}:{} to {}:{}", entry.in_addr, plumbing.in_port, entry.out_addr, plumbing.out_port)
+        if let Some(plumbing) = entry.sockets.iter().find(|s| s.source == descriptor.source) {
+            log::debug!("Plumbing already defined for {}", display_source(entry.in_addr, &plumbing.source))
         } else {
-            let source_socket = SocketAddr::new(entry.in_addr, descriptor.in_port);
-
-            let out_addr = descriptor.out_addr.unwrap_or(entry.out_addr);
-            let target_socket = SocketAddr::new(out_addr, descriptor.out_port);
+            let source_desc = display_source(entry.in_addr, &descriptor.source);
+            let listener_targets = match descriptor.target {
+                PlumbingTarget::Allocated(port) => vec![vec![TargetAddr::Tcp(SocketAddr::new(entry.out_addr, port))]],
+                PlumbingTarget::Fixed(targets) => targets,
+            };
+            let replay = descriptor.connection.session.as_ref()
+                .map(|session| session.mode == SessionMode::Replay)
+                .unwrap_or(false);
+            if listener_targets.iter().all(Vec::is_empty) && !replay {
+                return Err(anyhow!("No target defined for {source_desc}"));
+            }
 
-            log::debug!("{}:{} -> {}:{}", entry.value().in_addr, descriptor.in_port, out_addr, descriptor.out_port);
+            let cgroup_name = match &descriptor.source {
+                SourceAddr::Port(port) => format!("{name}-{port}"),
+                SourceAddr::Range(first, last) => format!("{name}-{first}-{last}"),
+                SourceAddr::Unix(_) => format!("{name}-{}", descriptor.socket_name),
+            };
+            let cgroup = descriptor.resource.as_ref()
+                .and_then(|cfg| ResourceCgroup::for_resource(&cgroup_name, cfg))
+                .map(Arc::new);
+            let resource = SharedResource::new(CmdResource::try_from(descriptor.resource.as_ref())?
+                .in_cgroup(cgroup.clone())?);
+            // every socket starts its own resource, so both limits bound the same connections
+            let resource_max_connections = descriptor.resource.as_ref().and_then(|resource| resource.max_connections);
+            let max_connections = descriptor.connection.max_connections.into_iter()
+                .chain(resource_max_connections)
+                .min();
+            let shared = SocketShared {
+                resource,
+                // the resource is stopped once every listener of a port range is idle
+                counter: Arc::new(tokio::sync::Mutex::new(ConnectionCounter::new())),
+                limiter: Arc::new(ConnectionLimiter::new(max_connections, &descriptor.connection)),
+            };
 
-            let handle = tokio::spawn(async move {
-                let out = listen_address(source_socket, target_socket, descriptor.resource).await;
-                if let Err(err) = out {
-                    log::error!("Error listening address {source_socket}")
+            let options = ForwardOptions::from(&descriptor.connection);
+            let mut connect = ConnectOptions::from(&descriptor.connection);
+            connect.tls_acceptor = descriptor.connection.tls.as_ref()
+                .map(|config| tls::acceptor(config, name, self.local_ca.get()))
+                .transpose()
+                .with_context(|| format!("Error configuring TLS for {source_desc}"))?;
+            connect.target_tls = descriptor.connection.target_tls.as_ref()
+                .map(TargetTls::new)
+                .transpose()
+                .with_context(|| format!("Error configuring target TLS for {source_desc}"))?;
+            connect.access_log = descriptor.access_log.as_ref()
+                .map(|access_log| access_log.socket(name, &descriptor.socket_name));
+            if let Some(session) = &descriptor.connection.session {
+                match session.mode {
+                    SessionMode::Record => connect.recorder = Some(Recorder::open(&session.path, session.max_session_bytes)?),
+                    SessionMode::Replay => connect.replay = Some(Arc::new(Replay::load(&session.path)
+                        .with_context(|| format!("Error loading sessions for {source_desc}"))?)),
                 }
-            });
+            }
+
+            // every listener is bound before any of them is started, a failure leaves nothing running
+            log::info!("Starting listener for address {source_desc}");
+            let sources = match &descriptor.source {
+                SourceAddr::Range(first, last) => (*first..=*last).map(SourceAddr::Port).collect(),
+                source => vec![source.clone()],
+            };
+            if sources.len() != listener_targets.len() {
+                return Err(anyhow!("Targets of {source_desc} do not match its {} listeners", sources.len()));
+            }
+            let mut listeners = Vec::with_capacity(sources.len());
+            for (offset, (source, targets)) in sources.iter().zip(listener_targets).enumerate() {
+                let source_desc = display_source(entry.in_addr, source);
+                log::debug!("{source_desc} -> {targets:?}");
+                let balancer = Arc::new(Balancer::new(targets, &descriptor.balance));
+
+                let fd_name = match &descriptor.source {
+                    SourceAddr::Range(..) => format!("{name}/{}:{offset}", descriptor.socket_name),
+                    _ => format!("{name}/{}", descriptor.socket_name),
+                };
+                let (listener, owned_path) = self.bind_listener(&fd_name, entry.in_addr, source, descriptor.socket_mode)
+                    .with_context(|| format!("Error binding address {source_desc}"))?;
+                listeners.push((source_desc, listener, owned_path, balancer));
+            }
+
+            let owned_paths = listeners.iter().filter_map(|(_, _, owned_path, _)| owned_path.clone()).collect();
+            let mut balancers = Vec::with_capacity(listeners.len());
+            let mut handles = Vec::with_capacity(listeners.len());
+            for (source_desc, listener, owned_path, balancer) in listeners {
+                let shutdown = self.shutdown.subscribe();
+                let listener_balancer = balancer.clone();
+                let listener_shared = shared.clone();
+                let options = options.clone();
+                let connect = connect.clone();
+                handles.push(tokio::spawn(async move {
+                    let out = listen_address(listener, listener_balancer, listener_shared, options, connect, shutdown).await;
+                    if let Err(err) = out {
+                        log::error!("Error listening address {source_desc} - {err}")
+                    }
+                    if let Some(path) = owned_path {
+                        if let Err(err) = fs::remove_file(&path) {
+                            log::warn!("Error removing socket {path:?} - {err}");
+                        }
+                    }
+                }));
+                balancers.push(balancer);
+            }
             entry.sockets.push(MappedSocket {
-                in_port: descriptor.in_port,
-                out_port: descriptor.out_port,
-                handle,
+                name: descriptor.socket_name,
+                source: descriptor.source,
+                shaper: connect.shaper.clone(),
+                faults: connect.faults.clone(),
+                capture: connect.capture.clone(),
+                owned_paths,
+                balancers,
+                shared,
+                cgroup,
+                handles,
             })
         }
         Ok(())
     }
 
+    /// Binds the listener synchronously so that every configured address is bound when `attach` returns.
+    /// Along with the listener the path of the socket file to remove on termination is returned.
+    fn bind_listener(&self, fd_name: &str, in_addr: IpAddr, source: &SourceAddr, mode: Option<u32>) -> anyhow::Result<(Listener, Option<PathBuf>)> {
+        let mut inherited = self.inherited.lock().expect("Broken inherited mutex");
+        match source {
+            SourceAddr::Port(port) => {
+                let source = SocketAddr::new(in_addr, *port);
+                let listener = match inherited.take_tcp(fd_name, source)? {
+                    Some(listener) => {
+                        log::debug!("Using inherited socket for {source}");
+                        listener
+                    }
+                    None => std::net::TcpListener::bind(source)?,
+                };
+                listener.set_nonblocking(true)?;
+                Ok((Listener::Tcp(TcpListener::from_std(listener)?), None))
+            }
+            SourceAddr::Unix(path) => {
+                let (listener, owned_path) = match inherited.take_unix(fd_name, path)? {
+                    Some(listener) => {
+                        log::debug!("Using inherited socket for {path:?}");
+                        (listener, None)
+                    }
+                    None => {
+                        if path.exists() {
+                            fs::remove_file(path)
+                                .context("Could not remove old socket!")?;
+                        }
+                        let listener = std::os::unix::net::UnixListener::bind(path)?;
+                        if let Some(mode) = mode {
+                            fs::set_permissions(path, fs::Permissions::from_mode(mode))
+                                .with_context(|| format!("Error setting permissions of {path:?}"))?;
+                        }
+                        (listener, Some(path.clone()))
+                    }
+                };
+                listener.set_nonblocking(true)?;
+                Ok((Listener::Unix(UnixListener::from_std(listener)?, path.clone()), owned_path))
+            }
+            SourceAddr::Range(..) => Err(anyhow!("Port ranges are bound one port at a time")),
+        }
+    }
+
+    /// Binds the listener of a front-end routing connections to the plumbing, inherited sockets are used when available
+    pub(crate) fn bind_frontend(&self, fd_name: &str, source: SocketAddr) -> anyhow::Result<Listener> {
+        let (listener, _) = self.bind_listener(fd_name, source.ip(), &SourceAddr::Port(source.port()), None)
+            .with_context(|| format!("Error binding address {source}"))?;
+        Ok(listener)
+    }
+
+    /// Front-ends are awaited by `join` along with the plumbing listeners
+    pub(crate) fn track_frontend(&self, handle: JoinHandle<()>) {
+        self.frontends.lock().expect("Broken frontends mutex").push(handle);
+    }
+
+    pub(crate) fn shutdown_signal(&self) -> watch::Receiver<Option<Instant>> {
+        self.shutdown.subscribe()
+    }
+
+    /// Resource started by the socket of plumbing `name` listening on `port`
+    pub(crate) fn socket_resource(&self, name: &str, port: u16) -> Option<SharedResource> {
+        self.plumbing.get(name)?
+            .sockets.iter()
+            .find(|socket| socket.source.ports().map(|ports| ports.contains(&port)).unwrap_or(false))
+            .map(|socket| socket.shared.resource.clone())
+    }
+
+    /// Shaping of the socket `socket_name` of plumbing `name`
+    pub(crate) fn socket_shaper(&self, name: &str, socket_name: &str) -> Option<Arc<Shaper>> {
+        self.find_socket(name, socket_name, |socket| socket.shaper.clone())
+    }
+
+    /// Faults injected on the socket `socket_name` of plumbing `name`
+    pub(crate) fn socket_faults(&self, name: &str, socket_name: &str) -> Option<Arc<FaultInjector>> {
+        self.find_socket(name, socket_name, |socket| socket.faults.clone())
+    }
+
+    /// Capture switches of the socket `socket_name` of plumbing `name`, or of all its sockets
+    pub(crate) fn socket_captures(&self, name: &str, socket_name: Option<&str>) -> Option<Vec<Arc<CaptureSwitch>>> {
+        let plumbing = self.plumbing.get(name)?;
+        let captures: Vec<_> = plumbing.sockets.iter()
+            .filter(|socket| socket_name.map(|socket_name| socket.name == socket_name).unwrap_or(true))
+            .map(|socket| socket.capture.clone())
+            .collect();
+        Some(captures).filter(|captures| !captures.is_empty())
+    }
+
+    fn find_socket<T>(&self, name: &str, socket_name: &str, f: impl FnOnce(&MappedSocket) -> T) -> Option<T> {
+        self.plumbing.get(name)?
+            .sockets.iter()
+            .find(|socket| socket.name == socket_name)
+            .map(f)
+    }
+
+    pub fn list(&self) -> Vec<PlumbingSummary> {
+        self.plumbing.iter()
+            .map(|entry| PlumbingSummary {
+                name: entry.key().to_string(),
+                source: entry.in_addr,
+                sockets: entry.sockets.iter()
+                    .map(|socket| SocketSummary {
+                        source: display_source(entry.in_addr, &socket.source),
+                        targets: display_targets(&socket.balancers),
+                        usage: socket.cgroup.as_ref().map(|cgroup| cgroup.usage()),
+                        connections: socket.shared.limiter.active(),
+                        queued: socket.shared.limiter.queued(),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Socket files created by the listeners, inherited sockets are not included
+    pub fn owned_paths(&self) -> Vec<PathBuf> {
+        self.plumbing.iter()
+            .flat_map(|entry| entry.sockets.iter().flat_map(|socket| socket.owned_paths.clone()).collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// Stops accepting connections on every socket, in-flight connections are drained until `deadline`
+    /// and running resources are stopped afterwards
+    pub fn shutdown(&self, deadline: Instant) {
+        self.shutdown.send_replace(Some(deadline));
+    }
+
+    /// Waits for a shutdown to be requested and for every listener to terminate.
+    /// When every listener terminated on its own the daemon shuts down and an error is returned.
     pub async fn join(self) -> anyhow::Result<()> {
+        let mut shutdown = self.shutdown.subscribe();
+        let mut check = tokio::time::interval(Duration::from_secs(1));
+        let mut listeners_failed = false;
+        while shutdown.borrow_and_update().is_none() {
+            tokio::select! {
+                changed = shutdown.changed() => changed?,
+                _ = check.tick() => if self.listeners_terminated() {
+                    log::error!("Every listener terminated, shutting down");
+                    listeners_failed = true;
+                    self.shutdown(Instant::now());
+                },
+            }
+        }
         while !self.plumbing.is_empty() {
             let key = {
                 let entry = self.plumbing.iter().next().ok_or_else(|| anyhow!("Could not find any key in plumbing"))?;
@@ -115,30 +497,81 @@ impl Plumber {
 
             let (key, plumbing) = self.plumbing.remove(&key)
                 .ok_or_else(|| anyhow!("Could not find plumbing for entry {}", key))?;
-            for socket in plumbing.sockets {
-                let out = socket.handle.await;
-                if let Err(err) = out {
+            for handle in plumbing.sockets.into_iter().flat_map(|socket| socket.handles) {
+                if let Err(err) = handle.await {
                     log::error!("Join error - {err}");
                 }
             }
             log::debug!("{key} plumbing terminated");
         }
+        let frontends = std::mem::take(&mut *self.frontends.lock().expect("Broken frontends mutex"));
+        for handle in frontends {
+            if let Err(err) = handle.await {
+                log::error!("Join error - {err}");
+            }
+        }
+        if listeners_failed {
+            bail!("Every listener terminated");
+        }
         Ok(())
     }
+
+    /// Whether there are listeners and all of them terminated
+    fn listeners_terminated(&self) -> bool {
+        let mut handles = 0;
+        let mut running = false;
+        for entry in self.plumbing.iter() {
+            for handle in entry.sockets.iter().flat_map(|socket| &socket.handles) {
+                handles += 1;
+                running |= !handle.is_finished();
+            }
+        }
+        for handle in self.frontends.lock().expect("Broken frontends mutex").iter() {
+            handles += 1;
+            running |= !handle.is_finished();
+        }
+        handles > 0 && !running
+    }
 }
 
-async fn listen_address(source: SocketAddr, target:SocketAddr, resource: Option<ResourceConfig>) -> anyhow::Result<()> {
-    log::info!("Starting listener for address {source}");
-    let listener = TcpListener::bind(source).await?;
+fn display_source(in_addr: IpAddr, source: &SourceAddr) -> String {
+    match source {
+        SourceAddr::Port(port) => SocketAddr::new(in_addr, *port).to_string(),
+        SourceAddr::Range(first, last) => format!("{}-{last}", SocketAddr::new(in_addr, *first)),
+        SourceAddr::Unix(path) => format!("unix:{}", path.display()),
+    }
+}
 
-    let counter = Arc::new(tokio::sync::Mutex::new(ConnectionCounter::new()));
-    let mut resource = CmdResource::try_from(resource.as_ref())?;
+/// Targets of a port range are shown as ranges as well, from the targets of its first and last listener
+fn display_targets(balancers: &[Arc<Balancer>]) -> Vec<String> {
+    let (Some(first), Some(last)) = (balancers.first(), balancers.last()) else {
+        return Vec::new();
+    };
+    let (first, last): (Vec<_>, Vec<_>) = (first.targets().collect(), last.targets().collect());
+    (0..first.len().max(last.len()))
+        .filter_map(|idx| match (first.get(idx), last.get(idx)) {
+            (Some(TargetAddr::Tcp(first)), Some(TargetAddr::Tcp(last))) if first.ip() == last.ip() && first != last => {
+                Some(format!("{first}-{}", last.port()))
+            }
+            (Some(target), _) | (None, Some(target)) => Some(target.to_string()),
+            (None, None) => None,
+        })
+        .collect()
+}
 
-    loop {
-        let Some((stream, _)) = timeout(Duration::from_secs(30), listener.accept()).await? else {
+async fn listen_address(listener: Listener, balancer: Arc<Balancer>, shared: SocketShared, options: ForwardOptions, connect: ConnectOptions, mut shutdown: watch::Receiver<Option<Instant>>) -> anyhow::Result<()> {
+    let source = listener.local_addr()?;
+    let SocketShared { resource, counter, limiter } = shared;
+
+    while shutdown.borrow().is_none() {
+        let accepted = tokio::select! {
+            accepted = timeout(Duration::from_secs(30), listener.accept()) => accepted?,
+            _ = shutdown.changed() => break,
+        };
+        let Some((mut stream, peer)) = accepted else {
             if let Some(ts) = counter.lock().await.no_connections_since() {
                 if ts.add(Duration::from_secs(600)) < SystemTime::now() {
-                    resource.ensure_stopped()?;
+                    resource.ensure_stopped().await?;
                 }
             }
             continue;
@@ -147,18 +580,85 @@ async fn listen_address(source: SocketAddr, target:SocketAddr, resource: Option<
             let mut counter_guard = counter.lock().await;
             counter_guard.add_connection();
         }
-        resource.ensure_running().await?;
+        let resource = resource.clone();
         let cloned_counter_mtx = counter.clone();
+        let mut options = options.clone();
+        let connect = connect.clone();
+        let balancer = balancer.clone();
+        let limiter = limiter.clone();
         tokio::spawn(async move {
-            log::debug!("SOURCE: {source} TARGET: {target}");
-            let res = redirect_stream(stream, target).await;
+            let accepted = SystemTime::now();
+            let started = Instant::now();
+            let progress = Arc::new(Progress::default());
+            options.progress = Some(progress.clone());
+            let mut report = ConnectionReport::new(peer.to_string());
+            let res = async {
+                // injected refusals and blackholes neither take a connection slot nor start the resource
+                let faults = connect.faults.config();
+                if let Some(faults) = &faults {
+                    if faults.refuses() {
+                        faults::abort_on_close(stream.as_raw_fd());
+                        log::debug!("Injected refusal of the connection from {peer}");
+                        report.close_reason = CloseReason::Fault;
+                        return Ok(());
+                    }
+                    if faults.blackhole {
+                        report.close_reason = CloseReason::Fault;
+                        return match forward::discard(stream, &options).await {
+                            Ok(discarded) => {
+                                log::debug!("Blackholed connection from {peer}, {} bytes discarded", discarded.upstream);
+                                Ok(())
+                            }
+                            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                                log::debug!("Blackholed connection from {peer} closed - {err}");
+                                Ok(())
+                            }
+                            Err(err) => Err(err).context("Error discarding blackholed connection"),
+                        };
+                    }
+                }
+                let disruptions = faults.filter(|faults| faults.disrupts());
+                report.close_reason = CloseReason::Refused;
+                let _permit = limiter.acquire().await
+                    .with_context(|| format!("Refusing connection from {peer}"))?;
+                report.close_reason = CloseReason::Error;
+                if let Some(replay) = &connect.replay {
+                    report.target = Some(String::from("replay"));
+                    // the client goes through the same accept steps as a forwarded connection
+                    read_proxied(&mut stream, &peer, &connect).await?;
+                    let client = accept_client(stream, &peer, &connect).await?;
+                    replay.serve(client, &peer, &progress).await?;
+                    report.close_reason = CloseReason::Closed;
+                    return Ok(());
+                }
+                report.cold_start = resource.ensure_running().await
+                    .context("Error starting resource")?;
+                redirect_stream(stream, peer, &balancer, &options, &connect, disruptions, &mut report).await
+            }.await;
             if let Err(err) = res {
-                log::error!("Error processing stream - {err}");
+                log::error!("Error processing stream - {err:#}");
+                report.error = Some(format!("{err:#}"));
+            }
+            if let Some(access_log) = &connect.access_log {
+                report.transferred = progress.transferred();
+                access_log.log(&report, accepted, started.elapsed());
             }
             let mut counter_guard = cloned_counter_mtx.lock().await;
             counter_guard.rem_connection();
         });
     }
+
+    drop(listener);
+    let deadline = shutdown.borrow().unwrap_or_else(Instant::now);
+    while counter.lock().await.no_connections_since().is_none() {
+        if Instant::now() >= deadline {
+            log::warn!("Closing {source} with connections still in-flight");
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    log::debug!("Listener {source} terminated");
+    resource.ensure_stopped().await
 }
 
 async fn timeout<F, O, E>(duration: Duration, future: F) -> Result<Option<O>, E>
@@ -168,22 +668,195 @@ async fn timeout<F, O, E>(duration: Duration, future: F) -> Result<Option<O>, E>
     tokio::time::timeout(duration, future).await.ok().transpose()
 }
 
-async fn redirect_stream(incoming: TcpStream, addr: impl ToSocketAddrs + Copy + Debug) -> anyhow::Result<()> {
-    let outgoing = TcpStream::connect(addr).await
-        .with_context(|| format!("Error connecting to address {addr:?}"))?;
+/// Addresses of the client connection, read from the PROXY protocol header when one is expected
+async fn read_proxied(incoming: &mut Stream, peer: &PeerAddr, connect: &ConnectOptions) -> anyhow::Result<Option<ProxiedAddrs>> {
+    if connect.accept_proxy {
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, proxy_protocol::read_header(incoming)).await
+            .context("Timeout reading PROXY protocol header")?
+            .with_context(|| format!("Error reading PROXY protocol header from {peer}"))
+    } else {
+        Ok(peer.socket_addr()
+            .zip(incoming.local_addr())
+            .map(|(source, destination)| ProxiedAddrs { source, destination }))
+    }
+}
+
+/// Completes the TLS handshake of the client when the socket terminates TLS
+async fn accept_client(incoming: Stream, peer: &PeerAddr, connect: &ConnectOptions) -> anyhow::Result<BoxedStream> {
+    Ok(match &connect.tls_acceptor {
+        Some(acceptor) => Box::new(tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(incoming)).await
+            .context("Timeout during TLS handshake")?
+            .with_context(|| format!("TLS handshake with {peer} failed"))?),
+        None => Box::new(incoming),
+    })
+}
 
-    let (mut in_reader, mut in_writer) = incoming.into_split();
-    let (mut out_reader, mut out_writer) = outgoing.into_split();
+/// Forwards a connection to the target, `report` is updated with the target and the close reason.
+/// `disruptions` are the faults injected on the forwarded streams.
+async fn redirect_stream(mut incoming: Stream, peer: PeerAddr, balancer: &Balancer, options: &ForwardOptions, connect: &ConnectOptions, disruptions: Option<FaultConfig>, report: &mut ConnectionReport) -> anyhow::Result<()> {
+    let client_fd = incoming.as_raw_fd();
 
-    futures::future::try_select(
-        Box::pin(tokio::io::copy(&mut in_reader, &mut out_writer)),
-        Box::pin(tokio::io::copy(&mut out_reader, &mut in_writer)),
-    )
-        .await
-        .map_err(|e| match e {
-            Either::Left((err, _fut)) => err,
-            Either::Right((err, _fut)) => err,
-        })
-        .context("Error during socket copy")?;
+    let proxied = read_proxied(&mut incoming, &peer, connect).await?;
+
+    // connections from unix sockets have no address to synthesize packets with
+    let captured = connect.capture.current()
+        .zip(proxied)
+        .map(|(capture, addrs)| capture.connection(addrs.source, addrs.destination));
+
+    let recorded = connect.recorder.as_ref().map(|recorder| recorder.session());
+
+    let shaped = connect.shaper.is_active();
+    let wrapped = shaped || disruptions.is_some() || captured.is_some() || recorded.is_some() || connect.mirror.is_some();
+    let (transferred, lease) = if connect.tls_acceptor.is_none() && connect.target_tls.is_none() && !wrapped {
+        report.close_reason = CloseReason::ConnectError;
+        let (outgoing, lease) = connect_target_stream(balancer, connect, proxied).await?;
+        report.target = Some(lease.addr().to_string());
+        report.close_reason = CloseReason::Error;
+        (forward_sockets(incoming, outgoing, options).await, lease)
+    } else {
+        let client = accept_client(incoming, &peer, connect).await?;
+        let client: BoxedStream = match &connect.mirror {
+            Some(mirror) => Box::new(MirrorConnection::open(mirror).stream(client)),
+            None => client,
+        };
+        let client: BoxedStream = match shaped {
+            true => Box::new(ShapedStream::new(client, connect.shaper.clone(), Direction::Upstream)),
+            false => client,
+        };
+        report.close_reason = CloseReason::ConnectError;
+        let (outgoing, lease) = connect_target_stream(balancer, connect, proxied).await?;
+        report.target = Some(lease.addr().to_string());
+        report.close_reason = CloseReason::Error;
+        let target: BoxedStream = match &connect.target_tls {
+            Some(target_tls) => {
+                let server_name = target_tls.server_name(lease.addr())?;
+                Box::new(tokio::time::timeout(HANDSHAKE_TIMEOUT, target_tls.connector().connect(server_name, outgoing)).await
+                    .context("Timeout during TLS handshake")?
+                    .with_context(|| format!("TLS handshake with {} failed", lease.addr()))?)
+            }
+            None => Box::new(outgoing),
+        };
+        let target: BoxedStream = match shaped {
+            true => Box::new(ShapedStream::new(target, connect.shaper.clone(), Direction::Downstream)),
+            false => target,
+        };
+        let (client, target): (BoxedStream, BoxedStream) = match &disruptions {
+            Some(faults) => {
+                let (client, target) = faults.wrap(client, target, Some(client_fd));
+                (Box::new(client), Box::new(target))
+            }
+            None => (client, target),
+        };
+        let (client, target): (BoxedStream, BoxedStream) = match &captured {
+            Some(connection) => (
+                Box::new(connection.stream(client, Direction::Upstream)),
+                Box::new(connection.stream(target, Direction::Downstream)),
+            ),
+            None => (client, target),
+        };
+        let (client, target): (BoxedStream, BoxedStream) = match &recorded {
+            Some(session) => (
+                Box::new(session.stream(client, Direction::Upstream)),
+                Box::new(session.stream(target, Direction::Downstream)),
+            ),
+            None => (client, target),
+        };
+        (forward(client, target, options).await, lease)
+    };
+    let transferred = match transferred {
+        Err(err) if faults::is_injected(&err) => {
+            log::debug!("Injected reset of the connection from {peer}");
+            report.close_reason = CloseReason::Fault;
+            return Ok(());
+        }
+        Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+            report.close_reason = CloseReason::Timeout;
+            return Err(err).context("Error during socket copy");
+        }
+        transferred => transferred.context("Error during socket copy")?,
+    };
+    report.close_reason = CloseReason::Closed;
+    log::debug!("Connection to {} closed, {} bytes sent, {} bytes received", lease.addr(), transferred.upstream, transferred.downstream);
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Connects to a target and sends the PROXY protocol header when enabled
+async fn connect_target_stream<'a>(balancer: &'a Balancer, connect: &ConnectOptions, proxied: Option<ProxiedAddrs>) -> anyhow::Result<(Stream, Lease<'a>)> {
+    let (mut outgoing, lease) = connect_with_retries(balancer, connect).await?;
+    log::debug!("Forwarding connection to {}", lease.addr());
+    if let Some(version) = connect.send_proxy {
+        outgoing.write_all(&proxy_protocol::encode(version, proxied)).await
+            .context("Error sending PROXY protocol header")?;
+    }
+    Ok((outgoing, lease))
+}
+
+/// Retries connecting to the targets with exponential backoff until the retries or the deadline are exhausted
+async fn connect_with_retries<'a>(balancer: &'a Balancer, options: &ConnectOptions) -> anyhow::Result<(Stream, Lease<'a>)> {
+    let deadline = options.deadline.map(|deadline| Instant::now() + deadline);
+    let mut backoff = options.backoff;
+    let mut attempt = 0;
+    loop {
+        match connect_target(balancer, deadline).await {
+            Ok(connected) => return Ok(connected),
+            Err(err) if attempt < options.retries && deadline.map(|deadline| Instant::now() + backoff < deadline).unwrap_or(true) => {
+                attempt += 1;
+                log::debug!("Connect attempt {attempt} failed, retrying in {backoff:?} - {err:#}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Connects to the first available target, failing targets are taken out of rotation
+async fn connect_target(balancer: &Balancer, deadline: Option<Instant>) -> anyhow::Result<(Stream, Lease<'_>)> {
+    let mut last_err = None;
+    for target in balancer.candidates() {
+        let connecting = Stream::connect(target.addr());
+        let connected = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, connecting).await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "connect deadline expired"))),
+            None => connecting.await,
+        };
+        match connected {
+            Ok(stream) => return Ok((stream, balancer.connected(target))),
+            Err(err) => {
+                log::warn!("Error connecting to address {} - {err}", target.addr());
+                balancer.failed(target);
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.map(anyhow::Error::from).unwrap_or_else(|| anyhow!("No target available")))
+        .context("Error connecting to any target")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_when_every_listener_terminated() {
+        let plumber = Plumber::new();
+        plumber.track_frontend(tokio::spawn(async {}));
+        let err = plumber.join().await.unwrap_err();
+        assert_eq!(err.to_string(), "Every listener terminated");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_running_while_a_listener_is_running() {
+        let plumber = Plumber::new();
+        let mut shutdown = plumber.shutdown_signal();
+        plumber.track_frontend(tokio::spawn(async move { shutdown.changed().await.ok(); }));
+        let started = Instant::now();
+        let stopping_plumber = plumber.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            stopping_plumber.shutdown(Instant::now());
+        });
+        plumber.join().await.unwrap();
+        assert_eq!(started.elapsed().as_secs(), 10);
+    }
+}
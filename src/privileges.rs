@@ -0,0 +1,93 @@
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+use crate::config::RunAsConfig;
+use crate::credentials::{check, lookup_group, lookup_user, user_groups};
+
+const CAP_NET_BIND_SERVICE: u32 = 10;
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Switches the daemon to the configured user.
+/// When `keep_bind_capability` is set `CAP_NET_BIND_SERVICE` is retained so that ports below 1024 can still be bound.
+pub fn drop_privileges(run_as: &RunAsConfig, keep_bind_capability: bool, owned_paths: &[&Path]) -> anyhow::Result<()> {
+    let user = lookup_user(&run_as.user)?;
+    let gid = run_as.group.as_deref()
+        .map(lookup_group)
+        .transpose()?
+        .unwrap_or(user.gid);
+    let groups = user_groups(&user)?;
+
+    // without root the group cannot be changed, it must already be the requested one
+    if unsafe { libc::geteuid() } == user.uid {
+        if unsafe { libc::getegid() } != gid {
+            bail!("Already running as {} but not with gid {gid}", user.name);
+        }
+        if !has_groups(&groups)? {
+            log::warn!("Already running as {}, supplementary groups left unchanged", user.name);
+        }
+        log::debug!("Already running as {}", user.name);
+        return Ok(());
+    }
+
+    for path in owned_paths {
+        std::os::unix::fs::chown(path, Some(user.uid), Some(gid))
+            .with_context(|| format!("Error changing owner of {path:?}"))?;
+    }
+
+    unsafe {
+        if keep_bind_capability {
+            check(libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0)).context("Error setting keep capabilities flag")?;
+        }
+        check(libc::setgroups(groups.len() as _, groups.as_ptr())).context("Error setting supplementary groups")?;
+        check(libc::setgid(gid)).context("Error setting gid")?;
+        check(libc::setuid(user.uid)).context("Error setting uid")?;
+    }
+
+    if keep_bind_capability {
+        let bind_capability = 1 << CAP_NET_BIND_SERVICE;
+        let header = CapUserHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+        let data = [
+            CapUserData { effective: bind_capability, permitted: bind_capability, inheritable: 0 },
+            CapUserData::default(),
+        ];
+        unsafe {
+            check(libc::syscall(libc::SYS_capset, &header, data.as_ptr()) as libc::c_int)
+                .context("Error setting capabilities")?;
+            check(libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0)).context("Error resetting keep capabilities flag")?;
+        }
+    }
+
+    log::info!("Dropped privileges to user {} (uid {}, gid {gid})", user.name, user.uid);
+    Ok(())
+}
+
+/// Whether the supplementary groups of the process are exactly `groups`
+fn has_groups(groups: &[libc::gid_t]) -> anyhow::Result<bool> {
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    check(count).context("Error reading supplementary groups")?;
+    let mut current = vec![0 as libc::gid_t; count as usize];
+    let count = unsafe { libc::getgroups(count, current.as_mut_ptr()) };
+    check(count).context("Error reading supplementary groups")?;
+    current.truncate(count as usize);
+    current.sort_unstable();
+    let mut expected = groups.to_vec();
+    expected.sort_unstable();
+    expected.dedup();
+    current.dedup();
+    Ok(current == expected)
+}
@@ -0,0 +1,216 @@
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::config::ProxyProtocolVersion;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107;
+
+/// Original endpoints of a proxied connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxiedAddrs {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+/// Encodes the header sent to the target, endpoints are not available for unix sockets
+pub fn encode(version: ProxyProtocolVersion, addrs: Option<ProxiedAddrs>) -> Vec<u8> {
+    let addrs = addrs.map(same_family);
+    match version {
+        ProxyProtocolVersion::V1 => match addrs {
+            Some((source, destination)) => {
+                let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!("PROXY {family} {} {} {} {}\r\n", source.ip(), destination.ip(), source.port(), destination.port())
+                    .into_bytes()
+            }
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            match addrs {
+                Some((source, destination)) => {
+                    let mut body = Vec::with_capacity(36);
+                    let family = match (source.ip(), destination.ip()) {
+                        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                            body.extend_from_slice(&src.octets());
+                            body.extend_from_slice(&dst.octets());
+                            0x11
+                        }
+                        (src, dst) => {
+                            body.extend_from_slice(&to_ipv6(src).octets());
+                            body.extend_from_slice(&to_ipv6(dst).octets());
+                            0x21
+                        }
+                    };
+                    body.extend_from_slice(&source.port().to_be_bytes());
+                    body.extend_from_slice(&destination.port().to_be_bytes());
+                    header.extend_from_slice(&[0x21, family]);
+                    header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+                    header.extend_from_slice(&body);
+                }
+                None => header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]),
+            }
+            header
+        }
+    }
+}
+
+/// Reads a v1 or v2 header without consuming any byte past its end, `None` is returned for
+/// headers not carrying the original endpoints (`UNKNOWN` and `LOCAL`)
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<ProxiedAddrs>> {
+    // the shortest v1 header is `PROXY UNKNOWN\r\n`, reading the v2 signature length is always safe
+    let mut start = [0u8; 12];
+    reader.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(reader).await
+    } else if start.starts_with(V1_PREFIX) {
+        read_v1(reader, &start).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<ProxiedAddrs>> {
+    let mut head = [0u8; 4];
+    reader.read_exact(&mut head).await?;
+    let [ver_cmd, family, len_hi, len_lo] = head;
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    let mut body = vec![0u8; u16::from_be_bytes([len_hi, len_lo]) as usize];
+    reader.read_exact(&mut body).await?;
+    if ver_cmd & 0x0f == 0 {
+        return Ok(None);
+    }
+    let addrs = match family >> 4 {
+        0x1 if body.len() >= 12 => {
+            let ip = |offset: usize| IpAddr::from(<[u8; 4]>::try_from(&body[offset..offset + 4]).expect("Slice length checked"));
+            let port = |offset: usize| u16::from_be_bytes([body[offset], body[offset + 1]]);
+            Some(ProxiedAddrs {
+                source: SocketAddr::new(ip(0), port(8)),
+                destination: SocketAddr::new(ip(4), port(10)),
+            })
+        }
+        0x2 if body.len() >= 36 => {
+            let ip = |offset: usize| IpAddr::from(<[u8; 16]>::try_from(&body[offset..offset + 16]).expect("Slice length checked"));
+            let port = |offset: usize| u16::from_be_bytes([body[offset], body[offset + 1]]);
+            Some(ProxiedAddrs {
+                source: SocketAddr::new(ip(0), port(32)),
+                destination: SocketAddr::new(ip(16), port(34)),
+            })
+        }
+        0x1 | 0x2 => return Err(invalid("truncated PROXY protocol addresses")),
+        _ => None,
+    };
+    Ok(addrs)
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(reader: &mut R, start: &[u8]) -> io::Result<Option<ProxiedAddrs>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY protocol header too long"));
+        }
+        line.push(reader.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol header is not valid text"))?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, destination, source_port, destination_port] => {
+            let parse = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+                Ok(SocketAddr::new(
+                    ip.parse().map_err(|_| invalid("invalid PROXY protocol address"))?,
+                    port.parse().map_err(|_| invalid("invalid PROXY protocol port"))?,
+                ))
+            };
+            Ok(Some(ProxiedAddrs {
+                source: parse(source, source_port)?,
+                destination: parse(destination, destination_port)?,
+            }))
+        }
+        _ => Err(invalid("malformed PROXY protocol header")),
+    }
+}
+
+/// Both addresses must belong to the same family, ipv4 addresses are mapped to ipv6 when they differ
+fn same_family(addrs: ProxiedAddrs) -> (SocketAddr, SocketAddr) {
+    let ProxiedAddrs { source, destination } = addrs;
+    if source.is_ipv4() == destination.is_ipv4() {
+        (source, destination)
+    } else {
+        (
+            SocketAddr::new(IpAddr::V6(to_ipv6(source.ip())), source.port()),
+            SocketAddr::new(IpAddr::V6(to_ipv6(destination.ip())), destination.port()),
+        )
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(source: &str, destination: &str) -> ProxiedAddrs {
+        ProxiedAddrs { source: source.parse().unwrap(), destination: destination.parse().unwrap() }
+    }
+
+    async fn read(mut data: &[u8]) -> io::Result<(Option<ProxiedAddrs>, Vec<u8>)> {
+        let header = read_header(&mut data).await?;
+        Ok((header, data.to_vec()))
+    }
+
+    #[test]
+    fn encodes_v1() {
+        let header = encode(ProxyProtocolVersion::V1, Some(addrs("10.0.0.1:5000", "10.0.0.2:80")));
+        assert_eq!(header, b"PROXY TCP4 10.0.0.1 10.0.0.2 5000 80\r\n");
+        assert_eq!(encode(ProxyProtocolVersion::V1, None), b"PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn encodes_mixed_families_as_ipv6() {
+        let header = encode(ProxyProtocolVersion::V1, Some(addrs("10.0.0.1:5000", "[::1]:80")));
+        assert_eq!(header, b"PROXY TCP6 ::ffff:10.0.0.1 ::1 5000 80\r\n");
+    }
+
+    #[tokio::test]
+    async fn round_trips_both_versions() {
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for expected in [addrs("10.0.0.1:5000", "10.0.0.2:80"), addrs("[2001:db8::1]:5000", "[::1]:443")] {
+                let mut data = encode(version, Some(expected));
+                data.extend_from_slice(b"payload");
+                let (header, rest) = read(&data).await.unwrap();
+                assert_eq!(header, Some(expected));
+                assert_eq!(rest, b"payload");
+            }
+            let (header, rest) = read(&encode(version, None)).await.unwrap();
+            assert_eq!(header, None);
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_headers() {
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 10.0.0.1 10.0.0.2 5000\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 10.0.0.1 nope 5000 80\r\n").await.is_err());
+        assert!(read(&[b"PROXY ".as_slice(), &[b'x'; 120]].concat()).await.is_err());
+        let mut truncated = V2_SIGNATURE.to_vec();
+        truncated.extend_from_slice(&[0x21, 0x11, 0x00, 0x04, 1, 2, 3, 4]);
+        assert!(read(&truncated).await.is_err());
+    }
+}
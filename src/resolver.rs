@@ -1,17 +1,27 @@
 use std::collections::{BTreeMap, HashMap};
 use std::net::IpAddr;
+use std::sync::Arc;
+
+use anyhow::Context;
 
 use serde::Serialize;
 
-use crate::config::{NamePlumbingConfig, ResourceConfig, SocketConf};
-use crate::plumber::{Plumber, PlumbingDescriptor};
+use crate::access_log::AccessLog;
+use crate::config::{NamePlumbingConfig, ResourceConfig, SocketConf, SourceAddr};
+use crate::plumber::{Plumber, PlumbingDescriptor, PlumbingTarget};
 
 #[derive(Clone)]
 pub struct NameResolver {
-    config: BTreeMap<String, SocketConf<NamePlumbingConfig>>,
+    config: Arc<BTreeMap<String, NameEntry>>,
     plumber: Plumber,
 }
 
+/// Configuration of a name-mode plumbing, with its access log shared by all the names it resolves
+struct NameEntry {
+    conf: SocketConf<NamePlumbingConfig>,
+    access_log: Option<Arc<AccessLog>>,
+}
+
 #[derive(Serialize)]
 pub struct TemplateParams {
     source: EndpointParam,
@@ -34,20 +44,26 @@ impl NameResolver {
     pub fn new(
         config: BTreeMap<String, SocketConf<NamePlumbingConfig>>,
         plumber: Plumber,
-    ) -> Self {
-        Self { config, plumber }
+    ) -> anyhow::Result<Self> {
+        let config = config.into_iter()
+            .map(|(name, conf)| {
+                let access_log = conf.access_log.as_ref()
+                    .map(AccessLog::open)
+                    .transpose()
+                    .with_context(|| format!("Error configuring access log for {name}"))?
+                    .map(Arc::new);
+                anyhow::Ok((name, NameEntry { conf, access_log }))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { config: Arc::new(config), plumber })
     }
 
+    /// Sets up the plumbing of `name` when missing, its resources are rendered from the name
     pub fn resolve(&self, name: &str) -> Option<IpAddr> {
-        let conf_name = self.config.iter()
-            .find(|(entry_name, _)| name.ends_with(*entry_name));
-
-        let Some((matched_name, socket_conf)) = conf_name else {
-            return None;
-        };
+        let entry = self.config_for(name)?;
 
         let binding = self.plumber.resolve(name);
-        for (_, conf) in &socket_conf.sockets {
+        for (socket_name, conf) in &entry.conf.sockets {
             let setup = match conf.resource.setup.render_template(&TemplateParams {
                 source: EndpointParam { ip: binding.source },
                 target: EndpointParam { ip: binding.target },
@@ -67,15 +83,19 @@ impl NameResolver {
                 }
             };
             let out = self.plumber.attach(name, PlumbingDescriptor {
+                socket_name: socket_name.clone(),
                 in_addr: None,
-                in_port: conf.source,
+                source: SourceAddr::Port(conf.source),
+                socket_mode: None,
                 out_addr: None,
-                out_port: conf.target,
+                target: PlumbingTarget::Allocated(conf.target),
+                balance: Default::default(),
                 resource: Some(ResourceConfig {
-                    warmup_millis: conf.resource.warmup_millis,
                     setup,
-                    healthcheck_cmd: conf.resource.healthcheck_cmd.clone(),
+                    ..conf.resource.clone()
                 }),
+                connection: conf.connection.clone(),
+                access_log: entry.access_log.clone(),
             });
             if let Err(err) = out {
                 log::error!("Error binding address - {err}");
@@ -84,4 +104,22 @@ impl NameResolver {
 
         Some(binding.source)
     }
+
+    /// Address of the plumbing already set up for `name`, nothing is created
+    pub fn lookup(&self, name: &str) -> Option<IpAddr> {
+        self.config_for(name)?;
+        self.plumber.lookup(name)
+    }
+
+    /// Whether `name` belongs to a name-mode plumbing
+    pub fn manages(&self, name: &str) -> bool {
+        self.config_for(name).is_some()
+    }
+
+    /// Configuration whose key ends `name`
+    fn config_for(&self, name: &str) -> Option<&NameEntry> {
+        self.config.iter()
+            .find(|(entry_name, _)| name.ends_with(entry_name.as_str()))
+            .map(|(_, entry)| entry)
+    }
 }
\ No newline at end of file
@@ -1,11 +1,20 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
 use std::path::Path;
 use std::process::{Child, Command, Output, Stdio};
 
 use anyhow::Result;
 
+use crate::credentials::Credentials;
+
 pub struct CmdRunner {
     command: Command,
-    process: Option<Child>
+    process: Option<Child>,
+    cgroup_procs: Option<CString>,
+    credentials: Option<Credentials>,
+    pre_exec_installed: bool,
 }
 
 impl CmdRunner {
@@ -17,11 +26,46 @@ impl CmdRunner {
 
         Ok(Self {
             command,
-            process: None
+            process: None,
+            cgroup_procs: None,
+            credentials: None,
+            pre_exec_installed: false,
         })
     }
 
+    /// Moves the spawned process into the cgroup owning the given `cgroup.procs` file before exec
+    pub fn join_cgroup(&mut self, procs_path: &Path) -> Result<()> {
+        self.cgroup_procs = Some(CString::new(procs_path.as_os_str().as_bytes())?);
+        Ok(())
+    }
+
+    /// Switches the spawned process to the given credentials before exec
+    pub fn run_as(&mut self, credentials: Credentials) {
+        self.credentials = Some(credentials);
+    }
+
+    fn install_pre_exec(&mut self) {
+        if self.pre_exec_installed || (self.cgroup_procs.is_none() && self.credentials.is_none()) {
+            return;
+        }
+        let cgroup_procs = self.cgroup_procs.clone();
+        let credentials = self.credentials.clone();
+        // the cgroup must be joined while still privileged, credentials are applied afterwards
+        let hook = move || {
+            if let Some(ref procs_path) = cgroup_procs {
+                join_cgroup(procs_path)?;
+            }
+            if let Some(ref credentials) = credentials {
+                credentials.apply()?;
+            }
+            Ok(())
+        };
+        unsafe { self.command.pre_exec(hook) };
+        self.pre_exec_installed = true;
+    }
+
     pub fn start(&mut self) -> Result<()> {
+        self.install_pre_exec();
         log::debug!("Starting command {:?} with args {:?}", self.command.get_program(), self.command.get_args().collect::<Vec<_>>());
         let process = self.command.spawn()?;
         self.process = Some(process);
@@ -57,4 +101,20 @@ impl CmdRunner {
             .unwrap_or(false);
         Ok(running)
     }
-}
\ No newline at end of file
+}
+
+/// Moves the current process into a cgroup, only async-signal-safe calls are allowed between fork and exec
+fn join_cgroup(procs_path: &CString) -> io::Result<()> {
+    unsafe {
+        let fd = libc::open(procs_path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+        libc::close(fd);
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
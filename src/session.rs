@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use anyhow::{anyhow, bail, Context as _};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::forward::Progress;
+use crate::net::PeerAddr;
+use crate::shaping::Direction;
+
+/// Appends the sessions of the connections of a socket to a file, one JSON line per connection
+pub struct Recorder {
+    path: PathBuf,
+    file: Mutex<File>,
+    max_session_bytes: usize,
+}
+
+/// Exchanges of a connection, written to the session file once both of its streams are dropped
+pub struct RecordedSession {
+    recorder: Arc<Recorder>,
+    exchanges: Mutex<Exchanges>,
+}
+
+#[derive(Default)]
+struct Exchanges {
+    exchanges: Vec<Exchange>,
+    bytes: usize,
+    /// The session exceeded the size limit, nothing more is recorded
+    discarded: bool,
+}
+
+/// Stream recording the data read from it into the session of its connection
+pub struct RecordedStream<S> {
+    inner: S,
+    session: Arc<RecordedSession>,
+    direction: Direction,
+}
+
+/// Recorded sessions served in place of the target
+pub struct Replay {
+    /// Responses recorded for each request, an empty request holds the data sent by the target on connect.
+    /// Each connection gets the responses of a request in recording order, the last one is repeated.
+    responses: HashMap<Vec<u8>, Vec<Vec<u8>>>,
+}
+
+enum Lookup {
+    /// The first `len` bytes of the buffer are a recorded request
+    Complete(usize),
+    /// The buffer is the beginning of a recorded request
+    Partial,
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionEntry {
+    exchanges: Vec<Exchange>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Exchange {
+    request: Payload,
+    response: Payload,
+}
+
+/// Bytes stored as a string when they are valid UTF-8, as `{"hex": "..."}` otherwise
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(try_from = "PayloadRepr", into = "PayloadRepr")]
+struct Payload(Vec<u8>);
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum PayloadRepr {
+    Text(String),
+    Binary { hex: String },
+}
+
+impl Recorder {
+    pub fn open(path: &Path, max_session_bytes: usize) -> anyhow::Result<Arc<Self>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("Error opening session file {path:?}"))?;
+        Ok(Arc::new(Self { path: path.to_path_buf(), file: Mutex::new(file), max_session_bytes }))
+    }
+
+    pub fn session(self: &Arc<Self>) -> Arc<RecordedSession> {
+        Arc::new(RecordedSession { recorder: self.clone(), exchanges: Mutex::new(Exchanges::default()) })
+    }
+
+    fn append(&self, entry: &SessionEntry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        // a single write keeps the lines of concurrent connections apart
+        self.file.lock().expect("Broken recorder mutex").write_all(&line)?;
+        Ok(())
+    }
+}
+
+impl RecordedSession {
+    /// Wraps a stream of the connection, `direction` is the flow of the data read from it
+    pub fn stream<S>(self: &Arc<Self>, inner: S, direction: Direction) -> RecordedStream<S> {
+        RecordedStream { inner, session: self.clone(), direction }
+    }
+
+    fn record(&self, direction: Direction, data: &[u8]) {
+        let mut session = self.exchanges.lock().expect("Broken recorder mutex");
+        if session.discarded {
+            return;
+        }
+        session.bytes += data.len();
+        if session.bytes > self.recorder.max_session_bytes {
+            log::warn!("Session larger than {} bytes not recorded in {:?}", self.recorder.max_session_bytes, self.recorder.path);
+            *session = Exchanges { discarded: true, ..Exchanges::default() };
+            return;
+        }
+        let exchanges = &mut session.exchanges;
+        let new_exchange = match direction {
+            // a request following a response starts a new exchange
+            Direction::Upstream => exchanges.last().map(|exchange| !exchange.response.0.is_empty()).unwrap_or(true),
+            Direction::Downstream => exchanges.is_empty(),
+        };
+        if new_exchange {
+            exchanges.push(Exchange::default());
+        }
+        let exchange = exchanges.last_mut().expect("Missing exchange");
+        match direction {
+            Direction::Upstream => exchange.request.0.extend_from_slice(data),
+            Direction::Downstream => exchange.response.0.extend_from_slice(data),
+        }
+    }
+}
+
+impl Drop for RecordedSession {
+    fn drop(&mut self) {
+        let exchanges = std::mem::take(&mut self.exchanges.get_mut().expect("Broken recorder mutex").exchanges);
+        if exchanges.is_empty() {
+            return;
+        }
+        if let Err(err) = self.recorder.append(&SessionEntry { exchanges }) {
+            log::error!("Error writing session file {:?} - {err:#}", self.recorder.path);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RecordedStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = &buf.filled()[before..];
+        if !read.is_empty() {
+            self.session.record(self.direction, read);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RecordedStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl Replay {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Error opening session file {path:?}"))?;
+        let mut responses: HashMap<Vec<u8>, Vec<Vec<u8>>> = HashMap::new();
+        for (idx, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: SessionEntry = serde_json::from_str(&line)
+                .with_context(|| format!("Invalid session at line {}", idx + 1))?;
+            for exchange in entry.exchanges {
+                responses.entry(exchange.request.0).or_default().push(exchange.response.0);
+            }
+        }
+        log::debug!("Loaded {} recorded requests from {path:?}", responses.len());
+        Ok(Self { responses })
+    }
+
+    /// Answers the client with the recorded responses until it closes the connection or sends an unknown request
+    pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(&self, mut stream: S, peer: &PeerAddr, progress: &Progress) -> anyhow::Result<()> {
+        let mut served = HashMap::new();
+        if let Some(greeting) = self.next_response(&[], &mut served) {
+            stream.write_all(greeting).await?;
+            progress.add(Direction::Downstream, greeting.len());
+        }
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; 16 * 1024];
+        loop {
+            let read = stream.read(&mut chunk).await?;
+            if read == 0 {
+                if !buffer.is_empty() {
+                    log::warn!("Replay connection from {peer} closed with {} unanswered bytes", buffer.len());
+                }
+                return Ok(());
+            }
+            progress.add(Direction::Upstream, read);
+            buffer.extend_from_slice(&chunk[..read]);
+            while !buffer.is_empty() {
+                match self.lookup(&buffer) {
+                    Lookup::Complete(len) => {
+                        let response = self.next_response(&buffer[..len], &mut served).unwrap_or_default();
+                        buffer.drain(..len);
+                        stream.write_all(response).await?;
+                        progress.add(Direction::Downstream, response.len());
+                    }
+                    Lookup::Partial => break,
+                    Lookup::Unknown => bail!("No recorded response for request from {peer} - {:?}", String::from_utf8_lossy(&buffer)),
+                }
+            }
+        }
+    }
+
+    /// Prefers the longest recorded request, waits for more data while the buffer may still become a longer one
+    /// unless it is already a recorded request
+    fn lookup(&self, buffer: &[u8]) -> Lookup {
+        let complete = self.responses.keys()
+            .filter(|request| !request.is_empty() && buffer.starts_with(request))
+            .map(|request| request.len())
+            .max();
+        let longer = self.responses.keys()
+            .any(|request| request.len() > buffer.len() && request.starts_with(buffer));
+        match complete {
+            Some(len) if len == buffer.len() || !longer => Lookup::Complete(len),
+            _ if longer => Lookup::Partial,
+            _ => Lookup::Unknown,
+        }
+    }
+
+    /// `served` counts the responses already sent on the connection for each request
+    fn next_response(&self, request: &[u8], served: &mut HashMap<Vec<u8>, usize>) -> Option<&[u8]> {
+        let responses = self.responses.get(request)?;
+        let count = served.entry(request.to_vec()).or_default();
+        let idx = (*count).min(responses.len() - 1);
+        *count += 1;
+        responses.get(idx).map(Vec::as_slice)
+    }
+}
+
+impl TryFrom<PayloadRepr> for Payload {
+    type Error = anyhow::Error;
+
+    fn try_from(value: PayloadRepr) -> Result<Self, Self::Error> {
+        match value {
+            PayloadRepr::Text(text) => Ok(Self(text.into_bytes())),
+            PayloadRepr::Binary { hex } if !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) => Err(anyhow!("Invalid hex payload")),
+            PayloadRepr::Binary { hex } if hex.len() % 2 == 0 => Ok(Self(
+                (0..hex.len()).step_by(2)
+                    .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16).expect("Checked hex digits"))
+                    .collect(),
+            )),
+            PayloadRepr::Binary { .. } => Err(anyhow!("Odd length of hex payload")),
+        }
+    }
+}
+
+impl From<Payload> for PayloadRepr {
+    fn from(value: Payload) -> Self {
+        match String::from_utf8(value.0) {
+            Ok(text) => PayloadRepr::Text(text),
+            Err(err) => PayloadRepr::Binary {
+                hex: err.into_bytes().iter().map(|byte| format!("{byte:02x}")).collect(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay(exchanges: &[(&str, &str)]) -> Replay {
+        let mut responses: HashMap<Vec<u8>, Vec<Vec<u8>>> = HashMap::new();
+        for (request, response) in exchanges {
+            responses.entry(request.as_bytes().to_vec()).or_default().push(response.as_bytes().to_vec());
+        }
+        Replay { responses }
+    }
+
+    #[test]
+    fn looks_up_longest_request() {
+        let replay = replay(&[("GET", "short"), ("GET /a", "long")]);
+        assert!(matches!(replay.lookup(b"GE"), Lookup::Partial));
+        assert!(matches!(replay.lookup(b"GET"), Lookup::Complete(3)));
+        assert!(matches!(replay.lookup(b"GET /a"), Lookup::Complete(6)));
+        assert!(matches!(replay.lookup(b"GET /b"), Lookup::Complete(3)));
+        assert!(matches!(replay.lookup(b"GET /aGET"), Lookup::Complete(6)));
+        assert!(matches!(replay.lookup(b"POST"), Lookup::Unknown));
+    }
+
+    #[test]
+    fn serves_responses_in_order_per_connection() {
+        let replay = replay(&[("ping", "1"), ("ping", "2")]);
+        for _ in 0..2 {
+            let mut served = HashMap::new();
+            let responses: Vec<_> = (0..3).map(|_| replay.next_response(b"ping", &mut served).unwrap()).collect();
+            assert_eq!(responses, [b"1", b"2", b"2"]);
+        }
+        assert_eq!(replay.next_response(b"pong", &mut HashMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn replays_session() {
+        let replay = replay(&[("", "hello"), ("ping", "pong")]);
+        let (mut client, server) = tokio::io::duplex(1024);
+        let progress = Progress::default();
+        let serve = replay.serve(server, &PeerAddr::Unix, &progress);
+        let exchange = async {
+            client.write_all(b"pi").await?;
+            client.write_all(b"ngping").await?;
+            client.shutdown().await?;
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await?;
+            anyhow::Ok(received)
+        };
+        let (served, received) = tokio::join!(serve, exchange);
+        served.unwrap();
+        assert_eq!(received.unwrap(), b"hellopongpong");
+        let transferred = progress.transferred();
+        assert_eq!((transferred.upstream, transferred.downstream), (8, 13));
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_request() {
+        let replay = replay(&[("ping", "pong")]);
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(b"pong").await.unwrap();
+        assert!(replay.serve(server, &PeerAddr::Unix, &Progress::default()).await.is_err());
+    }
+
+    #[test]
+    fn stores_payloads_as_text_or_hex() {
+        let text = serde_json::to_string(&Payload(b"GET /".to_vec())).unwrap();
+        assert_eq!(text, r#""GET /""#);
+        let binary = serde_json::to_string(&Payload(vec![0x16, 0xff, 0x00])).unwrap();
+        assert_eq!(binary, r#"{"hex":"16ff00"}"#);
+        let parsed: Payload = serde_json::from_str(&binary).unwrap();
+        assert_eq!(parsed.0, [0x16, 0xff, 0x00]);
+        assert!(serde_json::from_str::<Payload>(r#"{"hex":"16f"}"#).is_err());
+        assert!(serde_json::from_str::<Payload>(r#"{"hex":"zz"}"#).is_err());
+        assert!(serde_json::from_str::<Payload>(r#"{"hex":"+1"}"#).is_err());
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::config::ShapingConfig;
+
+const CHUNK_SIZE: usize = 16 * 1024;
+/// Data read ahead from a stream while waiting for the latency to elapse
+const MAX_PENDING: usize = 256 * 1024;
+/// Shortest wait for tokens, avoids waking up for a handful of bytes
+const MIN_WAIT: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    /// From the client to the target
+    Upstream,
+    /// From the target to the client
+    Downstream,
+}
+
+/// Shaping settings of a socket, shared by its connections and changeable while they are running
+pub struct Shaper {
+    state: Mutex<ShaperState>,
+}
+
+struct ShaperState {
+    config: Option<ShapingConfig>,
+    upstream: TokenBucket,
+    downstream: TokenBucket,
+}
+
+/// Bucket refilled at the configured rate, holding at most one second worth of bytes
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Stream delaying and rate limiting the data read from it
+pub struct ShapedStream<S> {
+    inner: S,
+    shaper: Arc<Shaper>,
+    direction: Direction,
+    bucket: TokenBucket,
+    /// Data read from `inner` along with the time it can be delivered
+    pending: VecDeque<(Instant, Vec<u8>)>,
+    pending_bytes: usize,
+    last_due: Instant,
+    eof: bool,
+    timer: Pin<Box<Sleep>>,
+}
+
+impl Shaper {
+    pub fn new(config: Option<ShapingConfig>) -> Self {
+        Self {
+            state: Mutex::new(ShaperState {
+                config,
+                upstream: TokenBucket::new(),
+                downstream: TokenBucket::new(),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> Option<ShapingConfig> {
+        self.state.lock().expect("Broken shaper mutex").config
+    }
+
+    /// Changes the shaping of the connections already shaped and enables it for the new connections
+    pub fn set_config(&self, config: Option<ShapingConfig>) {
+        self.state.lock().expect("Broken shaper mutex").config = config;
+    }
+
+    /// Connections are only shaped when opened while shaping is configured, other connections keep
+    /// being forwarded with zero copy
+    pub fn is_active(&self) -> bool {
+        self.config().is_some()
+    }
+
+    fn delay(&self) -> Duration {
+        let Some(config) = self.config() else {
+            return Duration::ZERO;
+        };
+        let jitter = match config.jitter_millis {
+            0 => 0,
+            jitter => fastrand::u64(0..=jitter),
+        };
+        Duration::from_millis(config.latency_millis + jitter)
+    }
+
+    /// Takes up to `wanted` bytes from the buckets, the time to wait is returned when none is available
+    fn take(&self, direction: Direction, connection: &mut TokenBucket, wanted: usize, now: Instant) -> Result<usize, Duration> {
+        let mut state = self.state.lock().expect("Broken shaper mutex");
+        let Some(config) = state.config else {
+            return Ok(wanted);
+        };
+        let (socket_rate, socket) = match direction {
+            Direction::Upstream => (config.upstream_bytes_per_sec, &mut state.upstream),
+            Direction::Downstream => (config.downstream_bytes_per_sec, &mut state.downstream),
+        };
+        let mut allowed = wanted;
+        let mut wait = Duration::ZERO;
+        for (rate, bucket) in [(socket_rate, socket), (config.connection_bytes_per_sec, connection)] {
+            let Some(rate) = rate.filter(|rate| *rate > 0) else {
+                continue;
+            };
+            let available = bucket.available(rate, now);
+            allowed = allowed.min(available as usize);
+            if available < 1.0 {
+                wait = wait.max(Duration::from_secs_f64((1.0 - available) / rate as f64));
+            }
+        }
+        if allowed == 0 {
+            return Err(wait.max(MIN_WAIT));
+        }
+        if let Some(rate) = socket_rate.filter(|rate| *rate > 0) {
+            let socket = match direction {
+                Direction::Upstream => &mut state.upstream,
+                Direction::Downstream => &mut state.downstream,
+            };
+            socket.available(rate, now);
+            socket.consume(allowed);
+        }
+        if config.connection_bytes_per_sec.filter(|rate| *rate > 0).is_some() {
+            connection.consume(allowed);
+        }
+        Ok(allowed)
+    }
+}
+
+impl TokenBucket {
+    fn new() -> Self {
+        Self { tokens: 0.0, last: Instant::now() }
+    }
+
+    fn available(&mut self, rate: u64, now: Instant) -> f64 {
+        let rate = rate as f64;
+        self.tokens = (self.tokens + now.saturating_duration_since(self.last).as_secs_f64() * rate).min(rate);
+        self.last = now;
+        self.tokens
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.tokens -= amount as f64;
+    }
+}
+
+impl<S> ShapedStream<S> {
+    /// Shapes the data read from `inner`, flowing in `direction`
+    pub fn new(inner: S, shaper: Arc<Shaper>, direction: Direction) -> Self {
+        Self {
+            inner,
+            shaper,
+            direction,
+            bucket: TokenBucket::new(),
+            pending: VecDeque::new(),
+            pending_bytes: 0,
+            last_due: Instant::now(),
+            eof: false,
+            timer: Box::pin(tokio::time::sleep(Duration::ZERO)),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> ShapedStream<S> {
+    /// Reads ahead so that the latency applies to the data as it arrives, not as it is consumed
+    fn fill(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        let mut chunk = [0u8; CHUNK_SIZE];
+        while !self.eof && self.pending_bytes < MAX_PENDING {
+            let mut buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut self.inner).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) if buf.filled().is_empty() => self.eof = true,
+                Poll::Ready(Ok(())) => {
+                    // data never overtakes the data read before it, whatever the jitter
+                    let due = (Instant::now() + self.shaper.delay()).max(self.last_due);
+                    self.last_due = due;
+                    self.pending_bytes += buf.filled().len();
+                    self.pending.push_back((due, buf.filled().to_vec()));
+                }
+                Poll::Ready(Err(err)) => return Err(err),
+                Poll::Pending => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ShapedStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        this.fill(cx)?;
+        loop {
+            let Some((due, data)) = this.pending.front_mut() else {
+                return if this.eof { Poll::Ready(Ok(())) } else { Poll::Pending };
+            };
+            let now = Instant::now();
+            if *due > now {
+                this.timer.as_mut().reset(*due);
+                ready!(this.timer.as_mut().poll(cx));
+                continue;
+            }
+            match this.shaper.take(this.direction, &mut this.bucket, data.len().min(buf.remaining()), now) {
+                Ok(taken) => {
+                    buf.put_slice(&data[..taken]);
+                    if taken == data.len() {
+                        this.pending.pop_front();
+                    } else {
+                        data.drain(..taken);
+                    }
+                    this.pending_bytes -= taken;
+                    return Poll::Ready(Ok(()));
+                }
+                Err(wait) => {
+                    this.timer.as_mut().reset(now + wait);
+                    ready!(this.timer.as_mut().poll(cx));
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ShapedStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shaper(config: ShapingConfig) -> Shaper {
+        Shaper::new(Some(config))
+    }
+
+    #[test]
+    fn refills_bucket_up_to_one_second() {
+        let mut bucket = TokenBucket::new();
+        let start = bucket.last;
+        assert_eq!(bucket.available(1000, start + Duration::from_millis(500)), 500.0);
+        assert_eq!(bucket.available(1000, start + Duration::from_secs(5)), 1000.0);
+        bucket.consume(300);
+        assert_eq!(bucket.available(1000, start + Duration::from_secs(5)), 700.0);
+    }
+
+    #[test]
+    fn shares_socket_rate_between_connections() {
+        let shaper = shaper(ShapingConfig {
+            upstream_bytes_per_sec: Some(1000),
+            connection_bytes_per_sec: Some(400),
+            ..ShapingConfig::default()
+        });
+        let now = Instant::now() + Duration::from_secs(2);
+        let mut connections: Vec<_> = (0..4).map(|_| TokenBucket::new()).collect();
+        let taken: Vec<_> = connections.iter_mut()
+            .map(|bucket| shaper.take(Direction::Upstream, bucket, 1000, now))
+            .collect();
+        assert_eq!(taken[..3], [Ok(400), Ok(400), Ok(200)]);
+        assert!(matches!(taken[3], Err(wait) if wait >= MIN_WAIT));
+        // the connection rate applies to each direction, the socket rate only to its own
+        assert_eq!(shaper.take(Direction::Downstream, &mut connections[3], 1000, now), Ok(400));
+    }
+
+    #[test]
+    fn ignores_unset_and_zero_rates() {
+        let mut bucket = TokenBucket::new();
+        let now = Instant::now();
+        assert_eq!(Shaper::new(None).take(Direction::Upstream, &mut bucket, 100, now), Ok(100));
+        let shaper = shaper(ShapingConfig { upstream_bytes_per_sec: Some(0), connection_bytes_per_sec: Some(0), ..ShapingConfig::default() });
+        assert_eq!(shaper.take(Direction::Upstream, &mut bucket, 100, now), Ok(100));
+        assert_eq!(bucket.tokens, 0.0);
+    }
+}
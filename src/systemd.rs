@@ -0,0 +1,169 @@
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::bail;
+
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sockets passed by systemd socket activation (`LISTEN_FDS`/`LISTEN_FDNAMES`)
+#[derive(Default)]
+pub struct ListenFds {
+    fds: Vec<(String, OwnedFd)>,
+}
+
+impl ListenFds {
+    /// Takes ownership of the sockets passed to this process, environment variables are cleared
+    /// so that they are not inherited by spawned resources
+    pub fn from_env() -> Self {
+        let pid_matches = std::env::var("LISTEN_PID").ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .map(|pid| pid == std::process::id())
+            .unwrap_or(false);
+        let count = std::env::var("LISTEN_FDS").ok()
+            .and_then(|count| count.parse::<RawFd>().ok())
+            .unwrap_or(0);
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+        for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(var);
+        }
+        if !pid_matches || count <= 0 {
+            return Self::default();
+        }
+
+        let mut names = names.split(':');
+        let fds = (LISTEN_FDS_START..LISTEN_FDS_START + count)
+            .map(|fd| (fd, names.next().unwrap_or("unknown").to_string()))
+            .filter(|(fd, name)| {
+                let is_socket = is_socket(*fd);
+                if !is_socket {
+                    log::warn!("Inherited fd {fd} ({name}) is not a socket, ignoring it");
+                }
+                is_socket
+            })
+            .map(|(fd, name)| {
+                unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+                (name, unsafe { OwnedFd::from_raw_fd(fd) })
+            })
+            .collect::<Vec<_>>();
+        log::info!("Inherited {} sockets from systemd", fds.len());
+        Self { fds }
+    }
+
+    /// Takes the TCP listener named `name` or, as a fallback, the one bound to `addr`
+    pub fn take_tcp(&mut self, name: &str, addr: SocketAddr) -> anyhow::Result<Option<std::net::TcpListener>> {
+        let fd = self.take(name, "TCP", &[libc::AF_INET, libc::AF_INET6], |fd| {
+            fd.try_clone().ok()
+                .and_then(|fd| std::net::TcpListener::from(fd).local_addr().ok())
+                .map(|local| local == addr)
+                .unwrap_or(false)
+        })?;
+        Ok(fd.map(std::net::TcpListener::from))
+    }
+
+    /// Takes the unix listener named `name` or, as a fallback, the one bound to `path`
+    pub fn take_unix(&mut self, name: &str, path: &Path) -> anyhow::Result<Option<std::os::unix::net::UnixListener>> {
+        let fd = self.take(name, "unix", &[libc::AF_UNIX], |fd| {
+            fd.try_clone().ok()
+                .and_then(|fd| std::os::unix::net::UnixListener::from(fd).local_addr().ok())
+                .map(|local| local.as_pathname() == Some(path))
+                .unwrap_or(false)
+        })?;
+        Ok(fd.map(std::os::unix::net::UnixListener::from))
+    }
+
+    /// Removes the stream socket of one of `domains` named `name` or, as a fallback, the first one `bound` accepts.
+    /// A socket named `name` of another kind is an error and stays in place.
+    fn take(&mut self, name: &str, kind: &str, domains: &[libc::c_int], bound: impl Fn(&OwnedFd) -> bool) -> anyhow::Result<Option<OwnedFd>> {
+        let is_kind = |fd: &OwnedFd| {
+            sockopt(fd, libc::SO_TYPE) == Some(libc::SOCK_STREAM)
+                && sockopt(fd, libc::SO_DOMAIN).map(|domain| domains.contains(&domain)).unwrap_or(false)
+        };
+        let position = match self.fds.iter().position(|(fd_name, _)| fd_name == name) {
+            Some(position) if !is_kind(&self.fds[position].1) => bail!("Inherited socket {name} is not a {kind} stream socket"),
+            Some(position) => Some(position),
+            None => self.fds.iter().position(|(_, fd)| is_kind(fd) && bound(fd)),
+        };
+        Ok(position.map(|position| self.fds.remove(position).1))
+    }
+}
+
+fn sockopt(fd: &OwnedFd, option: libc::c_int) -> Option<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe { libc::getsockopt(fd.as_raw_fd(), libc::SOL_SOCKET, option, (&mut value as *mut libc::c_int).cast(), &mut len) };
+    (ret == 0).then_some(value)
+}
+
+fn is_socket(fd: RawFd) -> bool {
+    let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+    let ret = unsafe { libc::fstat(fd, stat.as_mut_ptr()) };
+    ret == 0 && unsafe { stat.assume_init() }.st_mode & libc::S_IFMT == libc::S_IFSOCK
+}
+
+/// Sends a state update to the service manager, does nothing when not started by systemd
+pub fn notify(state: &str) {
+    let Ok(socket_path) = std::env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    let out = UnixDatagram::unbound().and_then(|socket| {
+        if let Some(abstract_name) = socket_path.strip_prefix('@') {
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(abstract_name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)
+        } else {
+            socket.send_to(state.as_bytes(), &socket_path)
+        }
+    });
+    if let Err(err) = out {
+        log::warn!("Error notifying systemd - {err}");
+    }
+}
+
+/// Watchdog interval requested by the service manager
+pub fn watchdog_interval() -> Option<Duration> {
+    let pid_matches = std::env::var("WATCHDOG_PID").ok()
+        .map(|pid| pid.parse::<u32>().ok() == Some(std::process::id()))
+        .unwrap_or(true);
+    std::env::var("WATCHDOG_USEC").ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| pid_matches && *usec > 0)
+        .map(Duration::from_micros)
+}
+
+/// Periodically pings the service manager watchdog at half the requested interval
+pub fn spawn_watchdog() {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    log::debug!("Watchdog enabled with interval {interval:?}");
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval / 2);
+        loop {
+            ticker.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_only_sockets_of_the_requested_kind() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let (udp, _) = std::os::unix::net::UnixDatagram::pair().unwrap();
+        let mut fds = ListenFds { fds: vec![("web".to_string(), OwnedFd::from(udp)), ("other".to_string(), OwnedFd::from(tcp))] };
+
+        let err = fds.take_tcp("web", addr).unwrap_err();
+        assert_eq!(err.to_string(), "Inherited socket web is not a TCP stream socket");
+        assert!(fds.take_unix("control", Path::new("/run/plumber.sock")).unwrap().is_none());
+        let listener = fds.take_tcp("api", addr).unwrap().unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+        assert_eq!(fds.fds.len(), 1);
+    }
+}
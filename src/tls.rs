@@ -0,0 +1,179 @@
+use std::fs;
+use std::io::{BufReader, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair, KeyUsagePurpose, SanType};
+use rustls::{ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig, ServerName};
+use time::{Duration, OffsetDateTime};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::config::{TargetAddr, TlsListenerConfig, TlsTargetConfig};
+
+const CA_CERT_FILE: &str = "ca.pem";
+const CA_KEY_FILE: &str = "ca-key.pem";
+const CA_COMMON_NAME: &str = "port-plumber local CA";
+/// Validity of the issued certificates, below the 825 days accepted by every client
+const LEAF_VALIDITY_DAYS: i64 = 365;
+
+/// Certificate authority issuing the certificates of listeners without a configured certificate,
+/// its certificate must be trusted by the clients (e.g. `ca.pem` imported in the browser)
+pub struct LocalCa {
+    signer: Certificate,
+}
+
+/// TLS originated towards the targets, the server name defaults to the target ip address
+#[derive(Clone)]
+pub struct TargetTls {
+    connector: TlsConnector,
+    server_name: Option<ServerName>,
+}
+
+impl LocalCa {
+    /// Loads the CA from `dir`, a new one is generated when missing
+    pub fn load_or_create(dir: &Path) -> anyhow::Result<Self> {
+        let cert_path = dir.join(CA_CERT_FILE);
+        let key_path = dir.join(CA_KEY_FILE);
+        if key_path.exists() {
+            let key_pem = fs::read_to_string(&key_path)
+                .with_context(|| format!("Error reading CA key {key_path:?}"))?;
+            let key_pair = KeyPair::from_pem(&key_pem)
+                .context("Error parsing CA key")?;
+            // Only the distinguished name and the key of the CA take part in signing, the certificate
+            // on disk is never parsed
+            let signer = Certificate::from_params(ca_params(key_pair))?;
+            log::debug!("Loaded local CA from {dir:?}");
+            return Ok(Self { signer });
+        }
+
+        let signer = Certificate::from_params(ca_params(KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)?))?;
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Error creating CA directory {dir:?}"))?;
+        fs::write(&cert_path, signer.serialize_pem()?)
+            .with_context(|| format!("Error writing CA certificate {cert_path:?}"))?;
+        fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&key_path)
+            .and_then(|mut file| file.write_all(signer.serialize_private_key_pem().as_bytes()))
+            .with_context(|| format!("Error writing CA key {key_path:?}"))?;
+        log::info!("Generated local CA, trust {cert_path:?} to accept its certificates");
+        Ok(Self { signer })
+    }
+
+    /// Issues a certificate for `name`, either a hostname or an ip address
+    fn issue(&self, name: &str) -> anyhow::Result<(Vec<rustls::Certificate>, PrivateKey)> {
+        let mut params = CertificateParams::new(Vec::<String>::new());
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.subject_alt_names = vec![match name.parse::<IpAddr>() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(name.to_string()),
+        }];
+        params.not_before = OffsetDateTime::now_utc() - Duration::days(1);
+        params.not_after = OffsetDateTime::now_utc() + Duration::days(LEAF_VALIDITY_DAYS);
+        let cert = Certificate::from_params(params)?;
+        let der = cert.serialize_der_with_signer(&self.signer)?;
+        Ok((vec![rustls::Certificate(der)], PrivateKey(cert.serialize_private_key_der())))
+    }
+}
+
+fn ca_params(key_pair: KeyPair) -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, CA_COMMON_NAME);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign, KeyUsagePurpose::DigitalSignature];
+    params.alg = &rcgen::PKCS_ECDSA_P256_SHA256;
+    params.key_pair = Some(key_pair);
+    params
+}
+
+/// Default location of the local CA
+pub fn default_ca_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("portplumber").join("ca"))
+}
+
+/// Builds the acceptor terminating TLS for the plumbing `name`
+pub fn acceptor(config: &TlsListenerConfig, name: &str, local_ca: Option<&LocalCa>) -> anyhow::Result<TlsAcceptor> {
+    let (certs, key) = match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => (load_certs(cert)?, load_key(key)?),
+        (None, None) => local_ca
+            .ok_or_else(|| anyhow!("No local CA available to issue a certificate for {name}"))?
+            .issue(name)?,
+        _ => return Err(anyhow!("Both cert and key must be configured")),
+    };
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid certificate")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+impl TargetTls {
+    pub fn new(config: &TlsTargetConfig) -> anyhow::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        match &config.ca {
+            Some(ca) => {
+                for cert in load_certs(ca)? {
+                    roots.add(&cert).context("Invalid CA certificate")?;
+                }
+            }
+            None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+            })),
+        }
+        let client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let server_name = config.server_name.as_deref()
+            .map(ServerName::try_from)
+            .transpose()
+            .context("Invalid server name")?;
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(client_config)),
+            server_name,
+        })
+    }
+
+    pub fn connector(&self) -> &TlsConnector {
+        &self.connector
+    }
+
+    /// Name verified against the certificate presented by `target`
+    pub fn server_name(&self, target: &TargetAddr) -> anyhow::Result<ServerName> {
+        match (&self.server_name, target) {
+            (Some(name), _) => Ok(name.clone()),
+            (None, TargetAddr::Tcp(addr)) => Ok(ServerName::IpAddress(addr.ip())),
+            (None, TargetAddr::Unix(path)) => Err(anyhow!("A server name is required to originate TLS over {path:?}")),
+        }
+    }
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<rustls::Certificate>> {
+    let file = fs::File::open(path)
+        .with_context(|| format!("Error opening certificate {path:?}"))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Error reading certificate {path:?}"))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {path:?}"));
+    }
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+fn load_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let file = fs::File::open(path)
+        .with_context(|| format!("Error opening key {path:?}"))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Error reading key {path:?}"))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("No private key found in {path:?}"))
+}